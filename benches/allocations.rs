@@ -0,0 +1,93 @@
+//! Counts the heap allocations made by entering inheritable scopes in a tight loop.
+//!
+//! Every scope has to allocate the `Arc` holding its value. Without pooling it would also allocate a new table,
+//! which costs as much as cloning a map. Inheriting doesn't write to the table, so it shouldn't allocate at all.
+//!
+//! Run with `cargo bench --bench allocations`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::HashMap,
+    hint::black_box,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tokio_inherit_task_local::{inherit_task_local, inheritable_task_local};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+inheritable_task_local! {
+    static OUTER: u32;
+    static INNER: u32;
+}
+
+const ITERATIONS: usize = 100_000;
+
+fn allocations_per_iteration(mut f: impl FnMut(usize)) -> f64 {
+    // Warm up, so one-time allocations aren't counted.
+    f(0);
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for i in 0..ITERATIONS {
+        f(i);
+    }
+    (ALLOCATIONS.load(Ordering::Relaxed) - before) as f64 / ITERATIONS as f64
+}
+
+fn main() {
+    let map = HashMap::from([(0u128, Arc::new(0u32))]);
+    let clone = allocations_per_iteration(|_| {
+        black_box(map.clone());
+    });
+    println!("cloning an unpooled map: {clone:.2} allocations per iteration");
+
+    let sync_scope = OUTER.sync_scope(1, || {
+        allocations_per_iteration(|i| {
+            INNER.sync_scope(i as u32, || black_box(INNER.get()));
+        })
+    });
+    println!("sync_scope: {sync_scope:.2} allocations per iteration");
+
+    let inherit = OUTER.sync_scope(1, || {
+        INNER.sync_scope(2, || {
+            allocations_per_iteration(|_| {
+                let _ = black_box(inherit_task_local(|| ()));
+            })
+        })
+    });
+    println!("inherit_task_local: {inherit:.2} allocations per iteration");
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let scope = runtime.block_on(OUTER.scope(1, async {
+        let mut total = 0.0;
+        for i in 0..ITERATIONS {
+            let before = ALLOCATIONS.load(Ordering::Relaxed);
+            INNER
+                .scope(i as u32, async { black_box(INNER.get()) })
+                .await;
+            total += (ALLOCATIONS.load(Ordering::Relaxed) - before) as f64;
+        }
+        total / ITERATIONS as f64
+    }));
+    println!("scope: {scope:.2} allocations per iteration");
+}
@@ -0,0 +1,48 @@
+//! Measures the overhead `.inherit_task_local()` adds to spawning a task, both when there's nothing to inherit and
+//! when there is.
+//!
+//! Run with `cargo bench --bench spawn`.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use tokio_inherit_task_local::{inheritable_task_local, FutureInheritTaskLocal as _};
+
+inheritable_task_local! {
+    static NUMBER: u32;
+}
+
+const ITERATIONS: u32 = 100_000;
+
+async fn time_per_spawn<F: std::future::Future<Output = ()> + Send + 'static>(
+    mut f: impl FnMut() -> F,
+) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        tokio::spawn(f()).await.unwrap();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let plain = time_per_spawn(|| async { black_box(()) }).await;
+        println!("tokio::spawn: {plain:?} per spawn");
+
+        let nothing = time_per_spawn(|| async { black_box(()) }.inherit_task_local()).await;
+        println!("inheriting nothing: {nothing:?} per spawn");
+
+        let something = NUMBER
+            .scope(
+                1,
+                time_per_spawn(|| async { black_box(()) }.inherit_task_local()),
+            )
+            .await;
+        println!("inheriting one value: {something:?} per spawn");
+    });
+}
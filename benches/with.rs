@@ -0,0 +1,49 @@
+//! Measures how long reading an inheritable task local with `with` takes, which is the hot path for things like
+//! looking up a request id for each log line.
+//!
+//! Run with `cargo bench --bench with`.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use tokio_inherit_task_local::inheritable_task_local;
+
+inheritable_task_local! {
+    static NUMBER: u32;
+    static OTHER_0: u32;
+    static OTHER_1: u32;
+    static OTHER_2: u32;
+    static OTHER_3: u32;
+}
+
+const ITERATIONS: u32 = 10_000_000;
+
+fn time_per_read() -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        NUMBER.with(|&n| black_box(n));
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let one = NUMBER.sync_scope(1, time_per_read);
+    println!("one value set: {one:?} per read");
+
+    let several = tokio_inherit_task_local::builder()
+        .set(&OTHER_0, 0)
+        .set(&OTHER_1, 1)
+        .set(&OTHER_2, 2)
+        .set(&OTHER_3, 3)
+        .set(&NUMBER, 4)
+        .sync_scope(time_per_read);
+    println!("five values set: {several:?} per read");
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let in_task = runtime.block_on(NUMBER.scope(1, async { time_per_read() }));
+    println!("inside a task: {in_task:?} per read");
+}
@@ -0,0 +1,99 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_core::Stream;
+use futures_util::stream::FuturesUnordered;
+
+use crate::{enter_sync_scope, inherited_task_local_table, TaskLocalInheritableTable};
+
+/// A [`FuturesUnordered`] which makes the inheritable task local values that were available when it was created
+/// available to every future inside of it, each time they're polled.
+///
+/// This type is created by [`FuturesUnorderedInheritTaskLocal::inherit_all`]. Requires the `futures` feature.
+#[derive(Debug)]
+pub struct InheritFuturesUnordered<F> {
+    inner: FuturesUnordered<F>,
+    task_locals: TaskLocalInheritableTable,
+}
+
+impl<F> InheritFuturesUnordered<F> {
+    /// Pushes a future into the set. It will have access to the same inheritable task local values as every other
+    /// future in the set.
+    pub fn push(&self, future: F) {
+        self.inner.push(future);
+    }
+
+    /// Returns the number of futures contained in the set.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns `true` if the set contains no futures.
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Returns the underlying [`FuturesUnordered`], discarding the inherited values.
+    pub fn into_inner(self) -> FuturesUnordered<F> {
+        self.inner
+    }
+}
+
+impl<F: Future> Stream for InheritFuturesUnordered<F> {
+    type Item = F::Output;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        enter_sync_scope(this.task_locals.clone(), || {
+            Pin::new(&mut this.inner).poll_next(cx)
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// Extends [`FuturesUnordered`] with a method that copies references to the current inheritable task local values
+/// into it. Requires the `futures` feature.
+pub trait FuturesUnorderedInheritTaskLocal<F> {
+    /// Copies references to the inheritable task local values that are currently available into this
+    /// [`FuturesUnordered`]. Every future in the set will have access to them when polled, even if the set itself is
+    /// polled somewhere those values aren't available.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// use futures_util::{stream::FuturesUnordered, StreamExt as _};
+    /// use tokio_inherit_task_local::{inheritable_task_local, FuturesUnorderedInheritTaskLocal as _};
+    ///
+    /// inheritable_task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// let futures = NUMBER.sync_scope(1, || {
+    ///     (0..3)
+    ///         .map(|i| async move { NUMBER.get() + i })
+    ///         .collect::<FuturesUnordered<_>>()
+    ///         .inherit_all()
+    /// });
+    /// let mut outputs = tokio::spawn(futures.collect::<Vec<_>>()).await.unwrap();
+    /// outputs.sort();
+    /// assert_eq!(outputs, [1, 2, 3]);
+    /// # }
+    /// ```
+    fn inherit_all(self) -> InheritFuturesUnordered<F>;
+}
+
+impl<F> FuturesUnorderedInheritTaskLocal<F> for FuturesUnordered<F> {
+    fn inherit_all(self) -> InheritFuturesUnordered<F> {
+        InheritFuturesUnordered {
+            inner: self,
+            task_locals: inherited_task_local_table(),
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::task::{Context, Poll};
+
+use tokio::task::futures::TaskLocalFuture;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{
+    enter_sync_scope, InheritableLocalKey, TaskLocalInheritableTable, INHERITABLE_TASK_LOCALS,
+};
+
+/// A [`Layer`] which sets an inheritable task local value for each request, so the inner service and any tasks which
+/// inherit from it can read it. The value is produced by calling an extractor on the request, for example to read a
+/// header.
+///
+/// Requires the `tower` feature.
+///
+/// # Example
+///
+/// ```
+/// # async fn dox() {
+/// use std::convert::Infallible;
+/// use tokio_inherit_task_local::{inheritable_task_local, InheritTaskLocalLayer};
+/// use tower::{ServiceBuilder, ServiceExt as _};
+///
+/// inheritable_task_local! {
+///     static REQUEST_ID: u64;
+/// }
+///
+/// let service = ServiceBuilder::new()
+///     .layer(InheritTaskLocalLayer::new(&REQUEST_ID, |request: &u64| *request))
+///     .service_fn(|_request: u64| async { Ok::<_, Infallible>(REQUEST_ID.get()) });
+/// assert_eq!(service.oneshot(7).await, Ok(7));
+/// # }
+/// ```
+pub struct InheritTaskLocalLayer<T: 'static, E> {
+    key: &'static InheritableLocalKey<T>,
+    extractor: E,
+}
+
+impl<T: 'static, E> InheritTaskLocalLayer<T, E> {
+    /// Creates a layer which sets `key` to the value returned by `extractor` for each request.
+    pub fn new(key: &'static InheritableLocalKey<T>, extractor: E) -> Self {
+        Self { key, extractor }
+    }
+}
+
+impl<T: 'static, E: Clone> Clone for InheritTaskLocalLayer<T, E> {
+    fn clone(&self) -> Self {
+        Self {
+            key: self.key,
+            extractor: self.extractor.clone(),
+        }
+    }
+}
+
+impl<T: 'static, E> std::fmt::Debug for InheritTaskLocalLayer<T, E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InheritTaskLocalLayer")
+            .field("key", &self.key.key_name())
+            .finish()
+    }
+}
+
+impl<S, T: 'static, E: Clone> Layer<S> for InheritTaskLocalLayer<T, E> {
+    type Service = InheritTaskLocalService<S, T, E>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        InheritTaskLocalService {
+            inner,
+            key: self.key,
+            extractor: self.extractor.clone(),
+        }
+    }
+}
+
+/// The [`Service`] created by [`InheritTaskLocalLayer`]. Requires the `tower` feature.
+pub struct InheritTaskLocalService<S, T: 'static, E> {
+    inner: S,
+    key: &'static InheritableLocalKey<T>,
+    extractor: E,
+}
+
+impl<S: Clone, T: 'static, E: Clone> Clone for InheritTaskLocalService<S, T, E> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            key: self.key,
+            extractor: self.extractor.clone(),
+        }
+    }
+}
+
+impl<S, T: 'static, E> std::fmt::Debug for InheritTaskLocalService<S, T, E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InheritTaskLocalService")
+            .field("key", &self.key.key_name())
+            .finish()
+    }
+}
+
+impl<S, T, E, Request> Service<Request> for InheritTaskLocalService<S, T, E>
+where
+    S: Service<Request>,
+    T: Send + Sync + 'static,
+    E: Fn(&Request) -> T,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = TaskLocalFuture<TaskLocalInheritableTable, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let value = (self.extractor)(&request);
+        let task_locals = TaskLocalInheritableTable::for_scope(self.key.key, self.key.slot(value));
+        // Some services do work in `call` before returning their future, so the value is set for both.
+        let future = enter_sync_scope(task_locals.clone(), || self.inner.call(request));
+        INHERITABLE_TASK_LOCALS.scope(task_locals, future)
+    }
+}
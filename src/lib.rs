@@ -29,27 +29,358 @@
 //! [`FutureInheritTaskLocal`].
 //!
 //! These inherited values ***DO NOT*** need to be [`Clone`]. Child tasks will inherit counted references to the original value.
+//!
+//! Inheriting works the same way on the current thread and multi thread runtimes, and inside of a
+//! [`LocalSet`](tokio::task::LocalSet). Futures which aren't [`Send`] can be given values and spawned with
+//! [`spawn_local`](tokio::task::spawn_local) like any other.
 
+use pin_project_lite::pin_project;
 use std::{
     any::Any,
+    cell::{Cell, RefCell, RefMut},
     collections::HashMap,
     fmt::{Debug, Formatter, Result as FmtResult},
-    future::Future,
+    future::{Future, IntoFuture},
+    hash::{BuildHasherDefault, Hasher},
     marker::PhantomData,
-    sync::Arc,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, PoisonError, RwLock,
+    },
+    task::{ready, Context, Poll},
 };
 use tokio::task::futures::TaskLocalFuture;
 
-/// This is mostly an implementation detail. It stores references to all of the inheritable task local values that are available to
-/// a given task. You are not meant to use this directly.
+type TaskLocalMap = HashMap<u128, Slot, BuildHasherDefault<KeyHasher>>;
+
+/// Keys are already FNV-1a hashes, computed by [`key_id`] from each key's name, declaration site and crate version,
+/// so there's no need to hash them again with SipHash. This makes every lookup, including the ones made by
+/// [`InheritableLocalKey::with`], noticeably cheaper. Folding the two halves of the identifier together relies on it
+/// being well distributed, which has to stay true if the way identifiers are derived ever changes.
+#[derive(Default)]
+struct KeyHasher(u64);
+
+impl Hasher for KeyHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        // Only reached if a key is hashed some way other than `write_u128`, keep it correct regardless.
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn write_u128(&mut self, key: u128) {
+        self.0 = (key as u64) ^ ((key >> 64) as u64);
+    }
+}
+
 #[derive(Clone)]
+struct Slot {
+    value: Arc<dyn Any + Send + Sync + 'static>,
+    /// Set by [`InheritableLocalKey::scope_override`]. Scopes leave a locked slot alone, and changing it in place
+    /// fails with [`InheritableAccessError::Locked`].
+    locked: bool,
+    /// Cleared for keys declared with `#[no_inherit]`. Such slots are left out of the tables of child tasks.
+    inherit: bool,
+    /// Set by [`InheritableLocalKey::scope_with_cleanup`]. Shared by every copy of the slot, so the callback runs once
+    /// the last of them is dropped.
+    _cleanup: Option<Arc<CleanupGuard>>,
+    /// For keys declared with `#[stacked]`, the slot which was current when this one was created.
+    below: Option<Arc<Slot>>,
+}
+
+/// Runs a callback when dropped.
+struct CleanupGuard(Mutex<Option<Box<dyn FnOnce() + Send>>>);
+
+impl Drop for CleanupGuard {
+    fn drop(&mut self) {
+        let on_drop = self
+            .0
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(on_drop) = on_drop {
+            on_drop();
+        }
+    }
+}
+
+/// This is mostly an implementation detail. It stores references to all of the inheritable task local values that are available to
+/// a given task. Most users won't need to use this directly, however it can be useful when writing a custom scheduler.
+///
+/// A copy of the current table can be obtained with [`current_table`], and installed again around a future or closure
+/// with [`enter_scope`](Self::enter_scope) or [`enter_sync_scope`](Self::enter_sync_scope).
+///
+/// # Example
+///
+/// A hand-rolled poll loop that makes the values available to the task each time it's polled, even though the loop
+/// itself runs without any inheritable task local values.
+///
+/// ```
+/// use std::{future::Future, pin::pin, task::{Context, Poll, Waker}};
+/// use tokio_inherit_task_local::{current_table, inheritable_task_local};
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// let table = NUMBER.sync_scope(1, || current_table()).unwrap();
+///
+/// let mut task = pin!(async { NUMBER.get() });
+/// let mut cx = Context::from_waker(Waker::noop());
+/// let output = loop {
+///     if let Poll::Ready(v) = table.clone().enter_sync_scope(|| task.as_mut().poll(&mut cx)) {
+///         break v;
+///     }
+/// };
+/// assert_eq!(output, 1);
+/// ```
 pub struct TaskLocalInheritableTable {
-    inner: HashMap<u128, Arc<(dyn Any + Send + Sync + 'static)>>,
+    /// Shared with every copy of this table until one of them is written to, so copying a table is cheap.
+    inner: RefCell<Arc<TaskLocalMap>>,
+    /// How many scopes were entered with this table, so the scope observer can be told when they end. Not copied by
+    /// clones.
+    scopes_entered: usize,
+    #[cfg(feature = "task-id")]
+    lineage: TaskLineage,
+}
+
+/// The tasks a table was made by, for debugging.
+#[cfg(feature = "task-id")]
+#[derive(Clone, Copy)]
+struct TaskLineage {
+    origin: Option<tokio::task::Id>,
+    parent: Option<tokio::task::Id>,
 }
 
 impl TaskLocalInheritableTable {
-    fn new(inner: HashMap<u128, Arc<(dyn Any + Send + Sync + 'static)>>) -> Self {
-        Self { inner }
+    fn new(inner: Arc<TaskLocalMap>) -> Self {
+        Self {
+            inner: RefCell::new(inner),
+            scopes_entered: 0,
+            #[cfg(feature = "task-id")]
+            lineage: TaskLineage {
+                origin: tokio::task::try_id(),
+                parent: None,
+            },
+        }
+    }
+
+    /// Returns the id of the tokio task this table was made in, or `None` if it was made outside of a task.
+    ///
+    /// A table inherited by a child task is copied in the task which spawned it, so inside the child this is the id
+    /// of the parent until the child makes a copy of its own, for example with [`current_table`]. Requires the
+    /// `task-id` feature.
+    #[cfg(feature = "task-id")]
+    pub fn origin_task_id(&self) -> Option<tokio::task::Id> {
+        self.lineage.origin
+    }
+
+    /// Returns the id of the task which made the table this one was copied from, if it was copied from a different
+    /// task. Following these ids shows which task each value was inherited from. Requires the `task-id` feature.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn dox() {
+    /// use tokio_inherit_task_local::{current_table, inheritable_task_local, FutureInheritTaskLocal as _};
+    ///
+    /// inheritable_task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// let (parent, child) = tokio::spawn(NUMBER.scope(1, async {
+    ///     let child = async { current_table().unwrap().parent_task_id() };
+    ///     (tokio::task::id(), tokio::spawn(child.inherit_task_local()).await.unwrap())
+    /// })).await.unwrap();
+    /// assert_eq!(child, Some(parent));
+    /// # }
+    /// ```
+    #[cfg(feature = "task-id")]
+    pub fn parent_task_id(&self) -> Option<tokio::task::Id> {
+        self.lineage.parent
+    }
+
+    /// Creates the table for a new scope which sets `slot` for `key`.
+    fn for_scope(key: u128, slot: Slot) -> Self {
+        let mut task_locals = current_task_local_table();
+        task_locals.observe_shadow(key);
+        task_locals.insert(key, slot);
+        task_locals.observe_enter(key);
+        task_locals
+    }
+
+    /// Calls the shadow hook if a scope is about to replace the value this table holds for `key`.
+    fn observe_shadow(&self, key: u128) {
+        if self.contains(key) {
+            let hook = *SHADOW_HOOK
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            hook(key);
+        }
+    }
+
+    /// Reports to the scope observer that a scope setting `key` has been entered with this table.
+    fn observe_enter(&mut self, key: u128) {
+        observe(ScopeEvent::Enter { key });
+        self.scopes_entered += 1;
+    }
+
+    /// Returns `true` if the value for `key` was pinned by [`InheritableLocalKey::scope_override`].
+    fn is_locked(&self, key: u128) -> bool {
+        self.inner
+            .borrow()
+            .get(&key)
+            .is_some_and(|current| current.locked)
+    }
+
+    /// Returns `true` if this table holds a value for the key with the given identifier.
+    pub fn contains(&self, key: u128) -> bool {
+        self.inner.borrow().contains_key(&key)
+    }
+
+    /// Returns the value held for the key with the given identifier, if there is one. The identifier of a key can be
+    /// found with [`InheritableLocalKey::id`].
+    pub fn get_raw(&self, key: u128) -> Option<Arc<dyn Any + Send + Sync + 'static>> {
+        self.inner.borrow().get(&key).map(|slot| slot.value.clone())
+    }
+
+    /// Returns how many keys have a value set in this table.
+    ///
+    /// Tables only store the keys which have been set, so their size depends on this rather than on how many keys
+    /// have been declared. Compare against [`registered_key_count`] to find out what fraction of the known keys a
+    /// task is using.
+    pub fn set_slots(&self) -> usize {
+        self.inner.borrow().len()
+    }
+
+    /// Returns how many values this table can hold without reallocating. See [`set_table_capacity_hint`].
+    pub fn capacity(&self) -> usize {
+        self.inner.borrow().capacity()
+    }
+
+    /// Makes the values in this table available to the future `f`, in place of any values set by the caller.
+    #[must_use = "this future must be awaited or the scope has no effect"]
+    pub fn enter_scope<F>(self, f: F) -> TaskLocalFuture<Self, F::IntoFuture>
+    where
+        F: IntoFuture,
+    {
+        INHERITABLE_TASK_LOCALS.scope(self, f.into_future())
+    }
+
+    /// Makes the values in this table available to the closure `f`, in place of any values set by the caller.
+    pub fn enter_sync_scope<F, R>(self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        enter_sync_scope(self, f)
+    }
+
+    /// Returns a reference to the values in this table.
+    fn share(&self) -> Arc<TaskLocalMap> {
+        self.inner.borrow().clone()
+    }
+
+    /// Borrows the values in this table for writing, copying them first if they're shared with another table.
+    fn map_mut(&self) -> RefMut<'_, TaskLocalMap> {
+        RefMut::map(self.inner.borrow_mut(), |inner| {
+            if Arc::get_mut(inner).is_none() {
+                // An empty map is most likely the shared one of an empty table, which is being written to for the first
+                // time.
+                let mut copy = if inner.is_empty() {
+                    hinted_pooled_map()
+                } else {
+                    pooled_map()
+                };
+                Arc::get_mut(&mut copy)
+                    .expect("pooled maps are never shared")
+                    .clone_from(inner);
+                *inner = copy;
+            }
+            Arc::get_mut(inner).expect("map was just made unique")
+        })
+    }
+
+    /// Stores `slot` for `key`, unless the current slot is locked.
+    fn insert(&self, key: u128, slot: Slot) {
+        let unused = {
+            let mut inner = self.map_mut();
+            match inner.get_mut(&key) {
+                Some(current) if current.locked => Some(slot),
+                Some(current) => Some(std::mem::replace(current, slot)),
+                None => inner.insert(key, slot),
+            }
+        };
+        drop_outside_borrow(unused);
+    }
+
+    /// Stores `slot` for `key` and returns the slot it replaced, unless the current slot is locked, in which case
+    /// nothing changes and `None` is returned.
+    fn replace(&self, key: u128, slot: Slot) -> Option<Option<Slot>> {
+        if self.is_locked(key) {
+            return None;
+        }
+        Some(self.map_mut().insert(key, slot))
+    }
+
+    /// Removes the value for `key`, unless the current slot is locked.
+    fn remove(&self, key: u128) -> Option<Arc<dyn Any + Send + Sync + 'static>> {
+        match self.inner.borrow().get(&key) {
+            Some(current) if !current.locked => {}
+            _ => return None,
+        }
+        let removed = self.map_mut().remove(&key);
+        removed.map(|slot| slot.value)
+    }
+}
+
+impl Clone for TaskLocalInheritableTable {
+    fn clone(&self) -> Self {
+        #[allow(unused_mut)]
+        let mut table = Self::new(self.share());
+        #[cfg(feature = "task-id")]
+        {
+            table.lineage.parent = if table.lineage.origin == self.lineage.origin {
+                self.lineage.parent
+            } else {
+                self.lineage.origin
+            };
+        }
+        table
+    }
+}
+
+impl Default for TaskLocalInheritableTable {
+    /// Creates a table without any values in it.
+    fn default() -> Self {
+        new_task_local_table()
+    }
+}
+
+impl Drop for TaskLocalInheritableTable {
+    fn drop(&mut self) {
+        observe_exits(self.scopes_entered);
+        let inner = self.inner.get_mut();
+        // If the map is shared, another table is still using it.
+        let Some(map) = Arc::get_mut(inner) else {
+            return;
+        };
+        if map.capacity() == 0 {
+            return;
+        }
+        // Clear before touching the pool, dropping the values could run arbitrary code.
+        map.clear();
+        let _ = MAP_POOL.try_with(|pool| {
+            if let Ok(mut pool) = pool.try_borrow_mut() {
+                if pool.len() < MAP_POOL_SIZE {
+                    // Once this table is gone the pool holds the only reference.
+                    pool.push(Arc::clone(inner));
+                }
+            }
+        });
     }
 }
 
@@ -60,7 +391,7 @@ impl Debug for TaskLocalInheritableTable {
     }
 }
 
-/// Extends any [`Future`] with a `'static` lifetime. Provides a method that copies references to the current inheritable task local
+/// Extends any [`Future`]. Provides a method that copies references to the current inheritable task local
 /// values into this [`Future`].
 pub trait FutureInheritTaskLocal: Future + Sized {
     /// Copies references to the inheritable task local values that are currently available into this [`Future`]. These
@@ -76,245 +407,3724 @@ pub trait FutureInheritTaskLocal: Future + Sized {
     /// tokio::spawn(a_future.inherit_task_local());
     /// # }
     /// ```
-    fn inherit_task_local(self) -> TaskLocalFuture<TaskLocalInheritableTable, Self>;
+    ///
+    /// If there are no inheritable task local values to copy, the future runs with an empty table. It shares one map
+    /// with every other empty table on the thread, so nothing is allocated for it until a value is stored.
+    ///
+    /// Inheriting takes constant time and doesn't copy the table. The child shares the table of its parent until one
+    /// of them changes a value, so `n` levels of nested inheritance use `O(n)` memory rather than `O(n * width)`,
+    /// where `width` is the number of values set. Keys declared with `#[no_inherit]` are the exception, if any of them
+    /// have a value the table is copied without them.
+    fn inherit_task_local(self) -> InheritTaskLocalFuture<Self>
+    where
+        Self: 'static;
+
+    /// Like [`inherit_task_local`](Self::inherit_task_local), but doesn't require the [`Future`] to be `'static`.
+    ///
+    /// This is meant for futures which are awaited in place rather than spawned, such as ones which borrow from the
+    /// caller. The values copied are the ones available when this method is called, and they're restored each time
+    /// the future is polled, even if it's polled somewhere those values aren't available.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # async fn func() {
+    /// use tokio_inherit_task_local::{inheritable_task_local, FutureInheritTaskLocal as _};
+    ///
+    /// inheritable_task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// let mut total = 0;
+    /// let future = NUMBER.sync_scope(1, || async { total += NUMBER.get() }.inherit_task_local_scoped());
+    /// future.await;
+    /// assert_eq!(total, 1);
+    /// # }
+    /// ```
+    fn inherit_task_local_scoped(self) -> InheritTaskLocalFuture<Self>;
+
+    /// Like [`inherit_task_local`](Self::inherit_task_local), but boxes the resulting [`Future`]. This erases its
+    /// type, which is useful for storing several inheriting futures in a single collection.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # async fn func() {
+    /// use std::{future::Future, pin::Pin};
+    /// use tokio_inherit_task_local::FutureInheritTaskLocal as _;
+    ///
+    /// let futures: Vec<Pin<Box<dyn Future<Output = u32> + Send>>> = vec![
+    ///     async { 1 }.inherit_task_local_boxed(),
+    ///     async { 2 }.inherit_task_local_boxed(),
+    /// ];
+    /// for future in futures {
+    ///     tokio::spawn(future);
+    /// }
+    /// # }
+    /// ```
+    fn inherit_task_local_boxed(self) -> Pin<Box<dyn Future<Output = Self::Output> + Send>>
+    where
+        Self: Send + 'static;
+
+    /// Like [`inherit_task_local`](Self::inherit_task_local), but also captures the current [`tracing::Span`] and
+    /// enters it each time the [`Future`] is polled. This is a shorthand for
+    /// `.inherit_task_local().instrument(Span::current())`.
+    ///
+    /// Requires the `tracing` feature.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # async fn func() {
+    /// use tokio_inherit_task_local::FutureInheritTaskLocal as _;
+    ///
+    /// let _span = tracing::info_span!("request").entered();
+    /// tokio::spawn(async { tracing::info!("inside request") }.inherit_task_local_and_span());
+    /// # }
+    /// ```
+    #[cfg(feature = "tracing")]
+    fn inherit_task_local_and_span(
+        self,
+    ) -> tracing::instrument::Instrumented<InheritTaskLocalFuture<Self>>
+    where
+        Self: 'static;
+
+    /// Like [`inherit_task_local`](Self::inherit_task_local), but only copies the values of the given keys. Other
+    /// values won't be available to the [`Future`], even if they're set for the current task.
+    ///
+    /// Keys declared with `#[no_inherit]` aren't copied even if they're listed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # async fn func() {
+    /// use tokio_inherit_task_local::{inheritable_task_local, FutureInheritTaskLocal as _};
+    ///
+    /// inheritable_task_local! {
+    ///     static REQUEST_ID: u64;
+    ///     static USER: String;
+    /// }
+    ///
+    /// let child = REQUEST_ID.sync_scope(1, || {
+    ///     USER.sync_scope(String::from("ferris"), || {
+    ///         async { (REQUEST_ID.get(), USER.is_set()) }.inherit_only(&[&REQUEST_ID])
+    ///     })
+    /// });
+    /// assert_eq!(tokio::spawn(child).await.unwrap(), (1, false));
+    /// # }
+    /// ```
+    fn inherit_only(self, keys: &[&'static dyn ErasedKey]) -> InheritTaskLocalFuture<Self>
+    where
+        Self: 'static;
 }
 
 impl<F> FutureInheritTaskLocal for F
 where
-    F: Future + 'static,
+    F: Future,
+{
+    fn inherit_task_local(self) -> InheritTaskLocalFuture<Self>
+    where
+        Self: 'static,
+    {
+        self.inherit_task_local_scoped()
+    }
+
+    fn inherit_task_local_scoped(self) -> InheritTaskLocalFuture<Self> {
+        InheritTaskLocalFuture::new(MaybeScoped::new(Some(inherited_task_local_table()), self))
+    }
+
+    fn inherit_task_local_boxed(self) -> Pin<Box<dyn Future<Output = Self::Output> + Send>>
+    where
+        Self: Send + 'static,
+    {
+        Box::pin(self.inherit_task_local())
+    }
+
+    #[cfg(feature = "tracing")]
+    fn inherit_task_local_and_span(
+        self,
+    ) -> tracing::instrument::Instrumented<InheritTaskLocalFuture<Self>>
+    where
+        Self: 'static,
+    {
+        tracing::Instrument::instrument(self.inherit_task_local(), tracing::Span::current())
+    }
+
+    fn inherit_only(self, keys: &[&'static dyn ErasedKey]) -> InheritTaskLocalFuture<Self>
+    where
+        Self: 'static,
+    {
+        let task_locals = current_table().map_or_else(empty_task_local_table, |current| {
+            let table = new_task_local_table();
+            {
+                let current = current.inner.borrow();
+                let mut inner = table.map_mut();
+                for key in keys {
+                    let id = key.id();
+                    if let Some(slot) = current.get(&id).filter(|slot| slot.inherit) {
+                        inner.insert(id, slot.clone());
+                    }
+                }
+            }
+            observe(ScopeEvent::Inherit {
+                key_count: table.inner.borrow().len(),
+            });
+            table
+        });
+        InheritTaskLocalFuture::new(MaybeScoped::new(Some(task_locals), self))
+    }
+}
+
+pin_project! {
+    #[project = MaybeScopedProj]
+    enum MaybeScoped<F: Future> {
+        Scoped {
+            #[pin]
+            inner: TaskLocalFuture<TaskLocalInheritableTable, F>,
+        },
+        // Used when there's nothing to set, so entering a scope would be wasted work.
+        Unscoped {
+            #[pin]
+            inner: F,
+        },
+    }
+}
+
+impl<F: Future> MaybeScoped<F> {
+    fn new(task_locals: Option<TaskLocalInheritableTable>, f: F) -> Self {
+        match task_locals {
+            Some(task_locals) => Self::Scoped {
+                inner: INHERITABLE_TASK_LOCALS.scope(task_locals, f),
+            },
+            None => Self::Unscoped { inner: f },
+        }
+    }
+}
+
+/// Makes the values available to the caller available to `f` each time it's polled. Unlike inheriting, values declared
+/// with `#[no_inherit]` are kept, since `f` is still run as part of the caller's task rather than as a new one.
+#[cfg(any(feature = "macros", feature = "time"))]
+fn keep_current<F: Future>(f: F) -> MaybeScoped<F> {
+    MaybeScoped::new(current_table(), f)
+}
+
+impl<F: Future> Future for MaybeScoped<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            MaybeScopedProj::Scoped { inner } => inner.poll(cx),
+            MaybeScopedProj::Unscoped { inner } => inner.poll(cx),
+        }
+    }
+}
+
+macro_rules! maybe_scoped_future {
+    ($(#[$attr:meta])* $name:ident) => {
+        pin_project! {
+            $(#[$attr])*
+            #[must_use = "futures do nothing unless you `.await` or poll them"]
+            pub struct $name<F: Future> {
+                #[pin]
+                inner: MaybeScoped<F>,
+                polled: bool,
+            }
+
+            impl<F: Future> PinnedDrop for $name<F> {
+                fn drop(this: Pin<&mut Self>) {
+                    if !this.polled {
+                        report_unpolled();
+                    }
+                }
+            }
+        }
+
+        impl<F: Future> $name<F> {
+            fn new(inner: MaybeScoped<F>) -> Self {
+                Self {
+                    inner,
+                    polled: false,
+                }
+            }
+        }
+
+        impl<F: Future> Future for $name<F> {
+            type Output = F::Output;
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+                let this = self.project();
+                *this.polled = true;
+                this.inner.poll(cx)
+            }
+        }
+
+        impl<F: Future> Debug for $name<F> {
+            fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+                f.debug_struct(stringify!($name)).finish()
+            }
+        }
+    };
+}
+
+maybe_scoped_future! {
+    /// The [`Future`] returned by [`FutureInheritTaskLocal::inherit_task_local`].
+    InheritTaskLocalFuture
+}
+
+maybe_scoped_future! {
+    /// The [`Future`] returned by [`InheritableLocalKey::scope_opt`].
+    ScopeOptFuture
+}
+
+pin_project! {
+    #[project = ScopeLazyProj]
+    enum ScopeLazyState<T: 'static, G, F: Future> {
+        Unpolled {
+            key: &'static InheritableLocalKey<T>,
+            make: Option<G>,
+            future: Option<F>,
+        },
+        Scoped {
+            #[pin]
+            inner: TaskLocalFuture<TaskLocalInheritableTable, F>,
+        },
+    }
+}
+
+pin_project! {
+    /// The [`Future`] returned by [`InheritableLocalKey::scope_lazy`].
+    pub struct ScopeLazyFuture<T: 'static, G, F: Future> {
+        #[pin]
+        state: ScopeLazyState<T, G, F>,
+    }
+}
+
+impl<T, G, F> Future for ScopeLazyFuture<T, G, F>
+where
+    T: Send + Sync + 'static,
+    G: FnOnce() -> T,
+    F: Future,
 {
-    fn inherit_task_local(self) -> TaskLocalFuture<TaskLocalInheritableTable, Self> {
-        let new_task_locals = INHERITABLE_TASK_LOCALS
-            .try_with(|task_locals| task_locals.clone())
-            .unwrap_or_else(|_| TaskLocalInheritableTable::new(HashMap::new()));
-        INHERITABLE_TASK_LOCALS.scope(new_task_locals, self)
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.project().state;
+        if let ScopeLazyProj::Unpolled { key, make, future } = state.as_mut().project() {
+            let key = *key;
+            let make = make.take().expect("value was already made");
+            // Never polled, so it's fine to move it into the scope.
+            let future = future.take().expect("value was already made");
+            let new_task_locals = TaskLocalInheritableTable::for_scope(key.key, key.slot(make()));
+            state.set(ScopeLazyState::Scoped {
+                inner: INHERITABLE_TASK_LOCALS.scope(new_task_locals, future),
+            });
+        }
+        match state.project() {
+            ScopeLazyProj::Scoped { inner } => inner.poll(cx),
+            ScopeLazyProj::Unpolled { .. } => unreachable!("the scope was just entered"),
+        }
+    }
+}
+
+impl<T, G, F: Future> Debug for ScopeLazyFuture<T, G, F> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("ScopeLazyFuture").finish()
     }
 }
 
-/// Returns a closure which has its own copy of the current table for inheritable task locals.
-/// Intended for use with [`tokio::task::spawn_blocking`].
+/// The [`Future`] returned by [`InheritableLocalKey::scope_owned`]. The table it runs with can be taken back out of
+/// it with [`into_table`](Self::into_table).
+pub struct ScopedFuture<F: Future> {
+    /// Boxed so this is [`Unpin`], which allows taking the table by value.
+    inner: Pin<Box<TaskLocalFuture<TaskLocalInheritableTable, F>>>,
+    /// How many scopes have to be reported as exited once this completes. Owned by the future rather than the table,
+    /// since the table can outlive the scope.
+    exits: usize,
+}
+
+impl<F: Future> ScopedFuture<F> {
+    fn new(mut task_locals: TaskLocalInheritableTable, f: F) -> Self {
+        let exits = std::mem::take(&mut task_locals.scopes_entered);
+        Self {
+            inner: Box::pin(INHERITABLE_TASK_LOCALS.scope(task_locals, f)),
+            exits,
+        }
+    }
+
+    /// Returns the table this future runs with, usually once it has completed. The table reflects any changes the
+    /// future made, and can be entered again with [`TaskLocalInheritableTable::enter_scope`] without rebuilding it.
+    pub fn into_table(mut self) -> TaskLocalInheritableTable {
+        self.inner
+            .as_mut()
+            .take_value()
+            .expect("the table is only taken once")
+    }
+}
+
+impl<F: Future> Future for ScopedFuture<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let output = ready!(self.inner.as_mut().poll(cx));
+        observe_exits(std::mem::take(&mut self.exits));
+        Poll::Ready(output)
+    }
+}
+
+impl<F: Future> Drop for ScopedFuture<F> {
+    fn drop(&mut self) {
+        observe_exits(self.exits);
+    }
+}
+
+impl<F: Future> Debug for ScopedFuture<F> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("ScopedFuture").finish()
+    }
+}
+
+/// Returns a closure which has its own copy of the current table for inheritable task locals.
+/// Intended for use with [`tokio::task::spawn_blocking`].
+///
+/// # Example
+/// ```
+/// use tokio_inherit_task_local::{inherit_task_local, inheritable_task_local};
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let output = NUMBER.scope(1, async move {
+///         tokio::task::spawn_blocking(inherit_task_local(|| NUMBER.get())).await.unwrap()
+///     }).await;
+///     assert_eq!(output, 1);
+/// }
+/// ```
+pub fn inherit_task_local<F, R>(f: F) -> impl FnOnce() -> R + Send + 'static
+where
+    F: FnOnce() -> R + Send + 'static,
+{
+    let new_task_locals = inherited_task_local_table();
+    move || enter_sync_scope(new_task_locals, f)
+}
+
+/// Runs the future `f` without access to any inheritable task local values, even if they were set by the caller.
+///
+/// Futures which inherit from within `f` will inherit nothing, which is useful for sandboxing spawned work.
+///
+/// # Example
+/// ```
+/// # async fn dox() {
+/// use tokio_inherit_task_local::{inheritable_task_local, scope_empty};
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// NUMBER.scope(1, async {
+///     scope_empty(async {
+///         assert!(NUMBER.try_with(|_| ()).is_err());
+///     }).await;
+/// }).await;
+/// # }
+/// ```
+#[must_use = "this future must be awaited or the scope has no effect"]
+pub fn scope_empty<F>(f: F) -> TaskLocalFuture<TaskLocalInheritableTable, F::IntoFuture>
+where
+    F: IntoFuture,
+{
+    INHERITABLE_TASK_LOCALS.scope(new_task_local_table(), f.into_future())
+}
+
+/// Returns a copy of the table of inheritable task local values for the current task, or `None` if there isn't one.
+///
+/// See [`TaskLocalInheritableTable`] for an example.
+pub fn current_table() -> Option<TaskLocalInheritableTable> {
+    INHERITABLE_TASK_LOCALS
+        .try_with(|task_locals| task_locals.clone())
+        .ok()
+        .or_else(|| restored_map().map(TaskLocalInheritableTable::new))
+}
+
+/// Spawns a new tokio task which inherits the current inheritable task local values, except for `key`, which is set
+/// to `value` for the new task only. This is a thin wrapper around [`tokio::spawn`].
+///
+/// If `key` is pinned by [`InheritableLocalKey::scope_override`] the pinned value is inherited instead.
+///
+/// # Example
+/// ```
+/// # async fn dox() {
+/// use tokio_inherit_task_local::{inheritable_task_local, spawn_with};
+///
+/// inheritable_task_local! {
+///     static REQUEST_ID: u64;
+///     static WORKER_ID: u32;
+/// }
+///
+/// let output = REQUEST_ID.scope(7, async {
+///     spawn_with(&WORKER_ID, 2, async { (REQUEST_ID.get(), WORKER_ID.get()) }).await.unwrap()
+/// }).await;
+/// assert_eq!(output, (7, 2));
+/// # }
+/// ```
+pub fn spawn_with<T, F>(
+    key: &'static InheritableLocalKey<T>,
+    value: T,
+    f: F,
+) -> tokio::task::JoinHandle<F::Output>
+where
+    T: Send + Sync,
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let mut new_task_locals = inherited_task_local_table();
+    new_task_locals.insert(key.key, key.slot(value));
+    new_task_locals.observe_enter(key.key);
+    tokio::spawn(INHERITABLE_TASK_LOCALS.scope(new_task_locals, f))
+}
+
+/// Spawns one tokio task for each item of `items`. Every task inherits the current inheritable task local values,
+/// and has `key` set to its item.
+///
+/// Each task is created by calling `f` with a reference to its item, before the item is moved into the scope. The
+/// handles are returned in the same order as the items. See [`spawn_with`] for spawning a single task this way.
+///
+/// # Example
+/// ```
+/// # async fn dox() {
+/// use tokio_inherit_task_local::{for_each_inheriting, inheritable_task_local};
+///
+/// inheritable_task_local! {
+///     static REQUEST_ID: u64;
+///     static SHARD: u32;
+/// }
+///
+/// let handles = REQUEST_ID.sync_scope(7, || {
+///     for_each_inheriting(0..3, &SHARD, |_| async { (REQUEST_ID.get(), SHARD.get()) })
+/// });
+/// let mut outputs = Vec::new();
+/// for handle in handles {
+///     outputs.push(handle.await.unwrap());
+/// }
+/// assert_eq!(outputs, [(7, 0), (7, 1), (7, 2)]);
+/// # }
+/// ```
+pub fn for_each_inheriting<I, T, F, Fut>(
+    items: I,
+    key: &'static InheritableLocalKey<T>,
+    mut f: F,
+) -> Vec<tokio::task::JoinHandle<Fut::Output>>
+where
+    I: IntoIterator<Item = T>,
+    T: Send + Sync,
+    F: FnMut(&T) -> Fut,
+    Fut: Future + Send + 'static,
+    Fut::Output: Send + 'static,
+{
+    items
+        .into_iter()
+        .map(|item| {
+            let future = f(&item);
+            spawn_with(key, item, future)
+        })
+        .collect()
+}
+
+/// Spawns a new OS thread with its own copy of the current table for inheritable task locals. This is a thin wrapper
+/// around [`std::thread::spawn`].
+///
+/// The new thread isn't a tokio task, however the inherited values are still available to
+/// [`with`](InheritableLocalKey::with) and [`try_with`](InheritableLocalKey::try_with) for the duration of `f`.
+///
+/// # Example
+/// ```
+/// use tokio_inherit_task_local::{inheritable_task_local, thread_spawn_inheriting};
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// let output = NUMBER.sync_scope(1, || thread_spawn_inheriting(|| NUMBER.get()))
+///     .join()
+///     .unwrap();
+/// assert_eq!(output, 1);
+/// ```
+pub fn thread_spawn_inheriting<F, T>(f: F) -> std::thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    std::thread::spawn(inherit_task_local(f))
+}
+
+/// Returns a closure which makes a copy of the current table for inheritable task locals available each time it's
+/// called. Unlike [`inherit_task_local`](fn@inherit_task_local), the returned closure can be called many times, from
+/// many threads at once, which makes it a good fit for thread pools such as `rayon`.
+///
+/// # Example
+/// ```
+/// use tokio_inherit_task_local::{inheritable_task_local, with_inherited_context};
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// let f = NUMBER.sync_scope(1, || with_inherited_context(|| NUMBER.get()));
+/// let outputs = (0..3)
+///     .map(|_| std::thread::spawn({
+///         let f = f.clone();
+///         move || f()
+///     }))
+///     .map(|handle| handle.join().unwrap())
+///     .collect::<Vec<_>>();
+/// assert_eq!(outputs, [1, 1, 1]);
+/// ```
+pub fn with_inherited_context<F, R>(f: F) -> impl Fn() -> R + Clone + Send + Sync + 'static
+where
+    F: Fn() -> R + Send + Sync + 'static,
+{
+    let context = CapturedContext::capture();
+    let f = Arc::new(f);
+    move || context.enter(|| f())
+}
+
+/// Captures the inheritable task local values which are currently available, so they can be restored later. This is
+/// equivalent to [`CapturedContext::capture`].
+///
+/// # Example
+/// ```
+/// # async fn dox() {
+/// use tokio_inherit_task_local::{capture, inheritable_task_local};
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// let snapshot = NUMBER.sync_scope(1, capture);
+/// let a = tokio::spawn(snapshot.scope(async { NUMBER.get() }));
+/// let b = tokio::spawn(snapshot.scope(async { NUMBER.get() + 1 }));
+/// assert_eq!((a.await.unwrap(), b.await.unwrap()), (1, 2));
+/// # }
+/// ```
+pub fn capture() -> CapturedContext {
+    CapturedContext::capture()
+}
+
+/// A copy of the inheritable task local values that were available when it was captured, which can be restored any
+/// number of times. This is useful for handing context to pre-spawned workers along with each item of work.
+///
+/// Unlike [`TaskLocalInheritableTable`], this type is [`Sync`], so it can be restored on several threads at once.
+/// Restoring it doesn't copy the values, and writes made while it's restored don't affect it.
+///
+/// A captured context only holds references to the values, nothing ties it to the runtime it was captured on. It can
+/// be restored on a different runtime, or outside of any runtime, even after the original one has shut down.
+///
+/// # Example
+/// ```
+/// # async fn dox() {
+/// use tokio_inherit_task_local::{inheritable_task_local, CapturedContext};
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// let context = NUMBER.sync_scope(1, CapturedContext::capture);
+/// assert_eq!(context.enter(|| NUMBER.get()), 1);
+/// assert_eq!(context.scope(async { NUMBER.get() }).await, 1);
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct CapturedContext {
+    task_locals: Arc<TaskLocalMap>,
+}
+
+impl CapturedContext {
+    /// Captures the inheritable task local values which are currently available. Values which aren't inherited by
+    /// child tasks, such as those declared with `#[no_inherit]`, aren't captured.
+    pub fn capture() -> Self {
+        Self {
+            task_locals: inherited_task_local_table().share(),
+        }
+    }
+
+    /// Makes the captured values available to the closure `f`.
+    pub fn enter<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        enter_sync_scope(self.table(), f)
+    }
+
+    /// Returns a closure which makes the captured values available to `f` when it's called, on whichever thread that
+    /// happens. This is the closure counterpart of [`scope`](Self::scope), for handing work to thread pools such as
+    /// `rayon` or [`std::thread::spawn`].
+    ///
+    /// # Example
+    /// ```
+    /// use tokio_inherit_task_local::{inheritable_task_local, CapturedContext};
+    ///
+    /// inheritable_task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// let context = NUMBER.sync_scope(1, CapturedContext::capture);
+    /// let f = context.wrap_closure(|| NUMBER.get());
+    /// assert_eq!(std::thread::spawn(f).join().unwrap(), 1);
+    /// ```
+    pub fn wrap_closure<F, R>(self, f: F) -> impl FnOnce() -> R
+    where
+        F: FnOnce() -> R,
+    {
+        move || self.enter(f)
+    }
+
+    /// Makes the captured values available to the future `f`.
+    #[must_use = "this future must be awaited or the scope has no effect"]
+    pub fn scope<F>(&self, f: F) -> TaskLocalFuture<TaskLocalInheritableTable, F::IntoFuture>
+    where
+        F: IntoFuture,
+    {
+        INHERITABLE_TASK_LOCALS.scope(self.table(), f.into_future())
+    }
+
+    /// Makes the captured values available to the future `f`, except for the ones set by `overrides`, which take
+    /// precedence over them. `overrides` receives an empty [`ScopeBuilder`], and returns it with the values to change.
+    ///
+    /// This is useful for deriving several contexts from one template, where most of the values stay the same.
+    ///
+    /// # Example
+    /// ```
+    /// # async fn dox() {
+    /// use tokio_inherit_task_local::{inheritable_task_local, CapturedContext};
+    ///
+    /// inheritable_task_local! {
+    ///     static SERVICE: &'static str;
+    ///     static REQUEST_ID: u64;
+    /// }
+    ///
+    /// let template = SERVICE.sync_scope("api", || REQUEST_ID.sync_scope(0, CapturedContext::capture));
+    /// let output = template
+    ///     .scope_with_overrides(|b| b.set(&REQUEST_ID, 7), async { (SERVICE.get(), REQUEST_ID.get()) })
+    ///     .await;
+    /// assert_eq!(output, ("api", 7));
+    /// # }
+    /// ```
+    #[must_use = "this future must be awaited or the scope has no effect"]
+    pub fn scope_with_overrides<O, F>(
+        &self,
+        overrides: O,
+        f: F,
+    ) -> TaskLocalFuture<TaskLocalInheritableTable, F::IntoFuture>
+    where
+        O: FnOnce(ScopeBuilder) -> ScopeBuilder,
+        F: IntoFuture,
+    {
+        INHERITABLE_TASK_LOCALS.scope(
+            overrides(ScopeBuilder::default()).build(self.table()),
+            f.into_future(),
+        )
+    }
+
+    /// Makes the captured values available on the current thread until the returned guard is dropped, without
+    /// wrapping the code which reads them in a closure. This is useful for callbacks which are invoked by another
+    /// library, see [`context!`] for an example.
+    ///
+    /// The restored values are only used by code running outside of any inheritable scope. Values available to the
+    /// current task take precedence over them. Guards should be dropped in the reverse order they were created in,
+    /// dropping one out of order panics in debug builds.
+    pub fn restore(&self) -> ContextGuard {
+        push_restored(self.task_locals.clone());
+        ContextGuard {
+            task_locals: Arc::as_ptr(&self.task_locals),
+            _not_send: PhantomData,
+        }
+    }
+
+    fn table(&self) -> TaskLocalInheritableTable {
+        TaskLocalInheritableTable::new(self.task_locals.clone())
+    }
+}
+
+impl Debug for CapturedContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("CapturedContext").finish()
+    }
+}
+
+/// Returned by [`CapturedContext::restore`]. The restored values are available on this thread until it's dropped.
+#[must_use = "the values are only restored until the guard is dropped"]
+pub struct ContextGuard {
+    /// The values that were restored, which have to be the most recent ones when this is dropped. Only compared, never
+    /// dereferenced.
+    task_locals: *const TaskLocalMap,
+    /// The values were restored on this thread, so they have to be removed from it too.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let was_last = pop_restored(self.task_locals);
+        debug_assert!(
+            was_last,
+            "ContextGuard dropped while values restored after it were still available"
+        );
+    }
+}
+
+impl Debug for ContextGuard {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("ContextGuard").finish()
+    }
+}
+
+/// A table of inheritable task local values which can be shared by many tasks, returned by
+/// [`InheritableLocalKey::scope_shared`]. Cloning it is cheap, and clones share the same table.
+#[derive(Clone)]
+pub struct SharedScope {
+    task_locals: Arc<TaskLocalMap>,
+}
+
+impl SharedScope {
+    /// Spawns a new tokio task with access to the values in this scope. This is a thin wrapper around
+    /// [`tokio::spawn`].
+    pub fn spawn<F>(&self, f: F) -> tokio::task::JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        tokio::spawn(self.scope(f))
+    }
+
+    /// Makes the values in this scope available to the future `f`.
+    #[must_use = "this future must be awaited or the scope has no effect"]
+    pub fn scope<F>(&self, f: F) -> TaskLocalFuture<TaskLocalInheritableTable, F::IntoFuture>
+    where
+        F: IntoFuture,
+    {
+        INHERITABLE_TASK_LOCALS.scope(
+            TaskLocalInheritableTable::new(self.task_locals.clone()),
+            f.into_future(),
+        )
+    }
+}
+
+impl Debug for SharedScope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("SharedScope").finish()
+    }
+}
+
+/// Returned by [`InheritableLocalKey::scope_counted`]. Reports how many tasks are still holding its value.
+#[derive(Clone)]
+pub struct DescendantCounter {
+    /// The table of the scope, which descendants share until one of them changes a value.
+    map: std::sync::Weak<TaskLocalMap>,
+    /// Each table which a descendant has copied holds another reference to this.
+    value: std::sync::Weak<dyn Any + Send + Sync>,
+}
+
+impl DescendantCounter {
+    /// Returns the approximate number of descendants which are still holding the value, not counting the scope
+    /// itself. Returns `0` once the value has been dropped.
+    ///
+    /// This is derived from reference counts, so it's only an estimate. Nested scopes inside the original one, and
+    /// references from [`get_arc`](InheritableLocalKey::get_arc), count as descendants too. Once the scope has
+    /// completed the count may be one lower than the number of descendants left.
+    pub fn count(&self) -> usize {
+        let value = self.value.strong_count();
+        if value == 0 {
+            return 0;
+        }
+        let sharing = self.map.strong_count().saturating_sub(1);
+        let copies = value - 1;
+        sharing + copies
+    }
+}
+
+impl Debug for DescendantCounter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("DescendantCounter")
+            .field("count", &self.count())
+            .finish()
+    }
+}
+
+/// A borrow of an inheritable task-local value, returned by [`InheritableLocalKey::borrow`].
+pub struct Ref<T> {
+    value: Arc<T>,
+}
+
+impl<T> Ref<T> {
+    /// Returns the counted reference this borrow holds.
+    pub fn into_arc(this: Self) -> Arc<T> {
+        this.value
+    }
+}
+
+impl<T> std::ops::Deref for Ref<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: Debug> Debug for Ref<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        Debug::fmt(&*self.value, f)
+    }
+}
+
+/// Returned by [`InheritableLocalKey::push`]. Restores the value which was replaced by the push when dropped.
+#[must_use = "the value is popped as soon as the guard is dropped"]
+pub struct PopGuard {
+    key: u128,
+    /// `None` if the push had no effect, otherwise the slot that was replaced.
+    previous: Option<Option<Slot>>,
+    /// The value that was pushed, which has to still be the current one when this is dropped. Only compared, never
+    /// dereferenced.
+    pushed: *const (),
+    /// The value was pushed onto this thread's current scope, so it has to be popped from it too.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for PopGuard {
+    fn drop(&mut self) {
+        let Some(previous) = self.previous.take() else {
+            return;
+        };
+        drop_outside_borrow(INHERITABLE_TASK_LOCALS.try_with(|task_locals| {
+            let is_current = task_locals
+                .inner
+                .borrow()
+                .get(&self.key)
+                .is_some_and(|slot| Arc::as_ptr(&slot.value).cast::<()>() == self.pushed);
+            debug_assert!(
+                is_current,
+                "PopGuard dropped while a value pushed after it was still set"
+            );
+            if !is_current {
+                return previous;
+            }
+            let mut inner = task_locals.map_mut();
+            match previous {
+                Some(slot) => inner.insert(self.key, slot),
+                None => inner.remove(&self.key),
+            }
+        }));
+    }
+}
+
+impl Debug for PopGuard {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("PopGuard").field("key", &self.key).finish()
+    }
+}
+
+/// Captures the inheritable task local values which are currently available, and returns a closure which restores
+/// them on whichever thread calls it.
+///
+/// The closure returns a [`ContextGuard`], and the values are available until it's dropped. This is a shorthand for
+/// [`CapturedContext::capture`] followed by [`CapturedContext::restore`], intended for callback based APIs where the
+/// callback can't be wrapped in a scope.
+///
+/// # Example
+/// ```
+/// use tokio_inherit_task_local::{context, inheritable_task_local};
+///
+/// inheritable_task_local! {
+///     static REQUEST_ID: u64;
+/// }
+///
+/// fn on_event(restore: impl Fn() -> tokio_inherit_task_local::ContextGuard) {
+///     let _guard = restore();
+///     assert_eq!(REQUEST_ID.get(), 7);
+/// }
+///
+/// let restore = REQUEST_ID.sync_scope(7, || context!());
+/// std::thread::spawn(move || on_event(restore)).join().unwrap();
+/// ```
+#[macro_export]
+macro_rules! context {
+    () => {{
+        let context = $crate::CapturedContext::capture();
+        move || context.restore()
+    }};
+}
+
+/// Computes a response with the values in `context` available, then sends it through `responder`. Returns the response
+/// back if the receiver was dropped, like [`Sender::send`](tokio::sync::oneshot::Sender::send).
+///
+/// This is intended for request/response patterns, where a requester sends a [`oneshot`](tokio::sync::oneshot)
+/// sender to a long running worker task. The worker didn't inherit the values of the requester, so the requester
+/// captures them with [`CapturedContext::capture`] and sends them along with the request.
+///
+/// Requires the `sync` feature.
+///
+/// # Example
+/// ```
+/// # async fn dox() {
+/// use tokio::sync::{mpsc, oneshot};
+/// use tokio_inherit_task_local::{inheritable_task_local, respond_with_context, CapturedContext};
+///
+/// inheritable_task_local! {
+///     static USER: String;
+/// }
+///
+/// let (requests, mut incoming) = mpsc::unbounded_channel::<(CapturedContext, oneshot::Sender<String>)>();
+/// tokio::spawn(async move {
+///     while let Some((context, responder)) = incoming.recv().await {
+///         let _ = respond_with_context(&context, responder, || format!("hello, {}", USER.get()));
+///     }
+/// });
+///
+/// let response = USER.scope(String::from("ferris"), async {
+///     let (responder, response) = oneshot::channel();
+///     requests.send((CapturedContext::capture(), responder)).unwrap();
+///     response.await.unwrap()
+/// }).await;
+/// assert_eq!(response, "hello, ferris");
+/// # }
+/// ```
+#[cfg(feature = "sync")]
+pub fn respond_with_context<R>(
+    context: &CapturedContext,
+    responder: tokio::sync::oneshot::Sender<R>,
+    f: impl FnOnce() -> R,
+) -> Result<(), R> {
+    responder.send(context.enter(f))
+}
+
+/// Runs both closures with [`rayon::join`], potentially in parallel, making a copy of the current table for
+/// inheritable task locals available to each of them.
+///
+/// Requires the `rayon` feature.
+///
+/// # Example
+/// ```
+/// use tokio_inherit_task_local::{inheritable_task_local, join_inheriting};
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// let output = NUMBER.sync_scope(1, || join_inheriting(|| NUMBER.get(), || NUMBER.get() + 1));
+/// assert_eq!(output, (1, 2));
+/// ```
+#[cfg(feature = "rayon")]
+pub fn join_inheriting<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA + Send,
+    B: FnOnce() -> RB + Send,
+    RA: Send,
+    RB: Send,
+{
+    let task_locals_a = inherited_task_local_table();
+    let task_locals_b = task_locals_a.clone();
+    rayon::join(
+        move || enter_sync_scope(task_locals_a, a),
+        move || enter_sync_scope(task_locals_b, b),
+    )
+}
+
+/// Runs the provided closure with [`tokio::task::block_in_place`], making the current table for inheritable task locals
+/// available inside of it.
+///
+/// The closure already runs within the current task, however this function explicitly restores the inheritable task
+/// locals inside of it, so [`with`](InheritableLocalKey::with) will work regardless of tokio internals.
+///
+/// Requires the `rt-multi-thread` feature.
+///
+/// # Panics
+///
+/// [`tokio::task::block_in_place`] panics when called from a [`current_thread`] runtime, so this function will too.
+///
+/// # Example
+/// ```
+/// use tokio_inherit_task_local::{block_in_place_inheriting, inheritable_task_local};
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// #[tokio::main(flavor = "multi_thread")]
+/// async fn main() {
+///     let output = NUMBER.scope(1, async move {
+///         block_in_place_inheriting(|| NUMBER.get())
+///     }).await;
+///     assert_eq!(output, 1);
+/// }
+/// ```
+///
+/// [`current_thread`]: fn@tokio::runtime::Builder::new_current_thread
+#[cfg(feature = "rt-multi-thread")]
+pub fn block_in_place_inheriting<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let new_task_locals = current_task_local_table();
+    tokio::task::block_in_place(move || enter_sync_scope(new_task_locals, f))
+}
+
+/// Requires `f` to complete before `duration` has elapsed, like [`tokio::time::timeout`]. The inheritable task local
+/// values available to the caller are made available to `f` each time it's polled.
+///
+/// [`tokio::time::timeout`] polls `f` from the task which awaits it, so the values would usually be available anyway.
+/// This guarantees it, even if the timeout is moved to another task before being awaited. Since `f` isn't a new task,
+/// values declared with `#[no_inherit]` are available to it as well.
+///
+/// Requires the `time` feature.
+///
+/// # Example
+/// ```
+/// # async fn dox() {
+/// use std::time::Duration;
+/// use tokio_inherit_task_local::{inheritable_task_local, timeout_inheriting};
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// let output = NUMBER.scope(1, async {
+///     timeout_inheriting(Duration::from_secs(1), async { NUMBER.get() }).await
+/// }).await;
+/// assert_eq!(output, Ok(1));
+/// # }
+/// ```
+#[cfg(feature = "time")]
+pub fn timeout_inheriting<F>(
+    duration: std::time::Duration,
+    f: F,
+) -> tokio::time::Timeout<InheritTaskLocalFuture<F>>
+where
+    F: Future,
+{
+    tokio::time::timeout(duration, InheritTaskLocalFuture::new(keep_current(f)))
+}
+
+/// Sets `key` to `value` for the future `f`, and runs it until it completes or `token` is cancelled, whichever happens
+/// first. Returns `None` if it was cancelled.
+///
+/// When cancelled, `f` is dropped and then `on_cancel` is called with the value, before the scope is exited. This is
+/// useful for graceful shutdown, where cleanup code needs the context of the work it's cleaning up after. If `f`
+/// completes first `on_cancel` isn't called.
+///
+/// Requires the `tokio-util` feature.
+///
+/// # Example
+/// ```
+/// # async fn dox() {
+/// use tokio_inherit_task_local::{inheritable_task_local, scope_until_cancelled};
+/// use tokio_util::sync::CancellationToken;
+///
+/// inheritable_task_local! {
+///     static CONNECTION_ID: u64;
+/// }
+///
+/// let token = CancellationToken::new();
+/// token.cancel();
+/// let output = scope_until_cancelled(&CONNECTION_ID, 7, token, std::future::pending::<()>(), |id| {
+///     println!("closing connection {id}");
+/// })
+/// .await;
+/// assert_eq!(output, None);
+/// # }
+/// ```
+#[cfg(feature = "tokio-util")]
+pub async fn scope_until_cancelled<T, F, C>(
+    key: &'static InheritableLocalKey<T>,
+    value: T,
+    token: tokio_util::sync::CancellationToken,
+    f: F,
+    on_cancel: C,
+) -> Option<F::Output>
+where
+    T: Send + Sync,
+    F: IntoFuture,
+    C: FnOnce(&T),
+{
+    let f = f.into_future();
+    key.scope(value, async move {
+        let output = {
+            let mut f = std::pin::pin!(f);
+            let mut cancelled = std::pin::pin!(token.cancelled());
+            std::future::poll_fn(|cx| {
+                if let Poll::Ready(output) = f.as_mut().poll(cx) {
+                    return Poll::Ready(Some(output));
+                }
+                cancelled.as_mut().poll(cx).map(|()| None)
+            })
+            .await
+        };
+        if output.is_none() {
+            key.with(on_cancel);
+        }
+        output
+    })
+    .await
+}
+
+/// Makes the future returned by an `async fn` inherit the inheritable task local values of its caller.
+///
+/// The function is rewritten to return its body wrapped with
+/// [`.inherit_task_local()`](FutureInheritTaskLocal::inherit_task_local), so the values are copied when the function is
+/// called rather than when the future is first polled. This means the future can be handed directly to
+/// [`tokio::spawn`].
+///
+/// Because [`inherit_task_local`](FutureInheritTaskLocal::inherit_task_local) is intended for spawning, the returned
+/// future must be `'static`. Functions which borrow their arguments, including `&self` methods, are not supported.
+///
+/// Requires the `macros` feature.
+///
+/// # Example
+/// ```
+/// use tokio_inherit_task_local::{inherit_task_local, inheritable_task_local};
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// #[inherit_task_local]
+/// async fn read_number() -> u32 {
+///     NUMBER.get()
+/// }
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let output = NUMBER.scope(1, async move {
+///         tokio::spawn(read_number()).await.unwrap()
+///     }).await;
+///     assert_eq!(output, 1);
+/// }
+/// ```
+#[cfg(feature = "macros")]
+pub use tokio_inherit_task_local_macros::inherit_task_local;
+
+/// Derives a `from_current` constructor which reads every field of a struct from an inheritable task local.
+///
+/// Each field must be annotated with `#[inheritable(KEY)]`, naming the key it's read from. The value is cloned out
+/// of the key, and `from_current` returns an [`InheritableAccessError`] if any key doesn't have a value. Annotate an
+/// `Option<T>` field with `#[inheritable(KEY, optional)]` to read it with [`get_opt`](InheritableLocalKey::get_opt)
+/// instead, leaving it as `None` when the key isn't set.
+///
+/// Requires the `macros` feature.
+///
+/// # Example
+/// ```
+/// use tokio_inherit_task_local::{inheritable_task_local, FromInheritableContext};
+///
+/// inheritable_task_local! {
+///     static USER: String;
+///     static REQUEST_ID: u64;
+/// }
+///
+/// #[derive(FromInheritableContext)]
+/// struct RequestContext {
+///     #[inheritable(USER)]
+///     user: String,
+///     #[inheritable(REQUEST_ID, optional)]
+///     request_id: Option<u64>,
+/// }
+///
+/// let context = USER.sync_scope(String::from("ferris"), RequestContext::from_current).unwrap();
+/// assert_eq!(context.user, "ferris");
+/// assert_eq!(context.request_id, None);
+/// assert!(RequestContext::from_current().is_err());
+/// ```
+#[cfg(feature = "macros")]
+pub use tokio_inherit_task_local_macros::FromInheritableContext;
+
+#[doc(hidden)]
+#[cfg(feature = "macros")]
+pub use tokio as __tokio;
+
+/// Wraps each branch of [`join_inheriting!`] and [`select_inheriting!`]. The unpolled hook isn't involved, since
+/// [`tokio::select!`] drops the branches which lost without polling them.
+#[doc(hidden)]
+#[cfg(feature = "macros")]
+pub fn __keep_current<F: Future>(f: F) -> impl Future<Output = F::Output> {
+    keep_current(f)
+}
+
+/// Like [`tokio::join!`], but each future inherits the inheritable task local values which are available where the
+/// macro is used.
+///
+/// The values are captured before any of the futures are polled, so every branch sees the same values regardless of
+/// the order they're polled in, and tasks they spawn with
+/// [`.inherit_task_local()`](FutureInheritTaskLocal::inherit_task_local) inherit them too. The futures still run as
+/// part of the current task, so values declared with `#[no_inherit]` are available to them.
+///
+/// Requires the `macros` feature.
+///
+/// # Example
+/// ```
+/// # async fn dox() {
+/// use tokio_inherit_task_local::{inheritable_task_local, join_inheriting, FutureInheritTaskLocal as _};
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// let (a, b) = NUMBER.scope(1, async {
+///     join_inheriting!(
+///         async { NUMBER.get() },
+///         tokio::spawn(async { NUMBER.get() + 1 }.inherit_task_local()),
+///     )
+/// }).await;
+/// assert_eq!((a, b.unwrap()), (1, 2));
+/// # }
+/// ```
+#[cfg(feature = "macros")]
+#[macro_export]
+macro_rules! join_inheriting {
+    ($($future:expr),+ $(,)?) => {
+        $crate::__tokio::join!($($crate::__keep_current($future)),+)
+    };
+}
+
+/// Like [`tokio::select!`], but each future inherits the inheritable task local values which are available where the
+/// macro is used. See [`join_inheriting!`] for details.
+///
+/// Only the basic form of [`tokio::select!`] is supported, in which every branch is written as
+/// `pattern = future => handler` and branches are separated by commas.
+///
+/// Requires the `macros` feature.
+///
+/// # Example
+/// ```
+/// # async fn dox() {
+/// use tokio_inherit_task_local::{inheritable_task_local, select_inheriting};
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// let output = NUMBER.scope(1, async {
+///     select_inheriting! {
+///         v = async { NUMBER.get() } => v,
+///         () = std::future::pending() => unreachable!(),
+///     }
+/// }).await;
+/// assert_eq!(output, 1);
+/// # }
+/// ```
+#[cfg(feature = "macros")]
+#[macro_export]
+macro_rules! select_inheriting {
+    ($($pattern:pat = $future:expr => $handler:expr),+ $(,)?) => {
+        $crate::__tokio::select! {
+            $($pattern = $crate::__keep_current($future) => { $handler })+
+        }
+    };
+}
+
+/// Returns a [`ScopeBuilder`], which can set the values of several inheritable task locals at once.
+///
+/// # Example
+/// ```
+/// # async fn dox() {
+/// use tokio_inherit_task_local::inheritable_task_local;
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+///     static NAME: String;
+/// }
+///
+/// tokio_inherit_task_local::builder()
+///     .set(&NUMBER, 1)
+///     .set(&NAME, String::from("foo"))
+///     .scope(async {
+///         assert_eq!(NUMBER.get(), 1);
+///         assert_eq!(NAME.get(), "foo");
+///     })
+///     .await;
+/// # }
+/// ```
+pub fn builder() -> ScopeBuilder {
+    ScopeBuilder::default()
+}
+
+/// Collects values for several inheritable task locals, then sets all of them for a future or closure at once.
+///
+/// This is equivalent to nesting calls to [`InheritableLocalKey::scope`], but only creates one table. This type is
+/// created by [`builder`].
+#[derive(Default)]
+pub struct ScopeBuilder {
+    values: Vec<(u128, Slot)>,
+}
+
+impl ScopeBuilder {
+    /// Sets a value `T` for the inheritable task-local `key`. If the same key is set more than once, the last value
+    /// wins.
+    pub fn set<T: Send + Sync>(mut self, key: &'static InheritableLocalKey<T>, value: T) -> Self {
+        self.values.push((key.key, key.slot(value)));
+        self
+    }
+
+    /// Sets the collected values as inheritable task-local values for the future `F`.
+    ///
+    /// See [`InheritableLocalKey::scope`] for more information.
+    #[must_use = "this future must be awaited or the scope has no effect"]
+    pub fn scope<F>(self, f: F) -> TaskLocalFuture<TaskLocalInheritableTable, F::IntoFuture>
+    where
+        F: IntoFuture,
+    {
+        INHERITABLE_TASK_LOCALS.scope(self.build(current_task_local_table()), f.into_future())
+    }
+
+    /// Sets the collected values as inheritable task-local values for the closure `F`.
+    ///
+    /// See [`InheritableLocalKey::sync_scope`] for more information.
+    pub fn sync_scope<F, R>(self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        enter_sync_scope(self.build(current_task_local_table()), f)
+    }
+
+    /// Creates a table holding only the collected values, ignoring any values available to the caller. The table can
+    /// be entered later with [`TaskLocalInheritableTable::enter_scope`] or
+    /// [`TaskLocalInheritableTable::enter_sync_scope`], which is useful for tests and custom schedulers.
+    ///
+    /// # Example
+    /// ```
+    /// use tokio_inherit_task_local::inheritable_task_local;
+    ///
+    /// inheritable_task_local! {
+    ///     static NUMBER: u32;
+    ///     static NAME: String;
+    /// }
+    ///
+    /// let table = tokio_inherit_task_local::builder()
+    ///     .set(&NUMBER, 1)
+    ///     .set(&NAME, String::from("foo"))
+    ///     .into_table();
+    /// let outer = NUMBER.sync_scope(2, || table.enter_sync_scope(|| (NUMBER.get(), NAME.get())));
+    /// assert_eq!(outer, (1, String::from("foo")));
+    /// ```
+    pub fn into_table(self) -> TaskLocalInheritableTable {
+        let task_locals = new_task_local_table();
+        for (key, slot) in self.values {
+            task_locals.insert(key, slot);
+        }
+        task_locals
+    }
+
+    fn build(self, mut task_locals: TaskLocalInheritableTable) -> TaskLocalInheritableTable {
+        for (key, slot) in self.values {
+            task_locals.observe_shadow(key);
+            task_locals.insert(key, slot);
+            task_locals.observe_enter(key);
+        }
+        task_locals
+    }
+}
+
+impl Debug for ScopeBuilder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        // Like the table, the values themselves aren't very useful to print.
+        f.debug_struct("ScopeBuilder").finish()
+    }
+}
+
+#[cfg(feature = "futures")]
+pub use futures_unordered::{FuturesUnorderedInheritTaskLocal, InheritFuturesUnordered};
+
+#[cfg(feature = "futures")]
+mod futures_unordered;
+
+#[cfg(feature = "tokio-util")]
+pub use task_tracker::TaskTrackerInheritExt;
+
+#[cfg(feature = "tokio-util")]
+mod task_tracker;
+
+#[cfg(feature = "tower")]
+pub use layer::{InheritTaskLocalLayer, InheritTaskLocalService};
+
+pub use shared::Shared;
+
+mod shared;
+
+#[cfg(feature = "tracing-subscriber")]
+pub use tracing_layer::SpanContextLayer;
+
+#[cfg(feature = "tracing-subscriber")]
+mod tracing_layer;
+
+#[cfg(feature = "tower")]
+mod layer;
+
+tokio::task_local! {
+    static INHERITABLE_TASK_LOCALS: TaskLocalInheritableTable
+}
+
+/// A key for inheritable task-local data.
+///
+/// This type is generated by the [`inheritable_task_local!`] macro.
+///
+/// Unlike [`std::thread::LocalKey`], `InheritableLocalKey` will
+/// _not_ lazily initialize the value on first access. Instead, the
+/// value is first initialized when the future containing
+/// the task-local is first polled by a futures executor, like Tokio.
+///
+/// Each key is identified by a `u128` derived from its [name](Self::key_name), the file, line and column it was
+/// declared at, and the version of the crate that declared it. The identifier is computed at compile time, so it's the
+/// same across builds and platforms, as long as the declaration doesn't move. Nothing
+/// needs to be registered at runtime, so every key is usable from any thread as soon as the program starts.
+///
+/// # Examples
+///
+/// ```
+/// # async fn dox() {
+/// # use tokio_inherit_task_local::inheritable_task_local;
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// NUMBER.scope(1, async move {
+///     assert_eq!(NUMBER.get(), 1);
+/// }).await;
+///
+/// NUMBER.scope(2, async move {
+///     assert_eq!(NUMBER.get(), 2);
+///
+///     NUMBER.scope(3, async move {
+///         assert_eq!(NUMBER.get(), 3);
+///     }).await;
+/// }).await;
+/// # }
+/// ```
+///
+/// [`std::thread::LocalKey`]: struct@std::thread::LocalKey
+pub struct InheritableLocalKey<T: 'static> {
+    key: u128,
+    name: &'static str,
+    inherit: bool,
+    /// Set for keys declared with `#[stacked]`, whose slots remember the values of outer scopes.
+    stacked: bool,
+    /// Produces the value used when none is set, for keys declared with an initializer.
+    init: Option<fn() -> T>,
+    /// The value produced by `init`, once it has been called.
+    lazy_default: OnceLock<Arc<dyn Any + Send + Sync + 'static>>,
+    /// Set once this key has been added to [`REGISTERED_KEYS`].
+    registered: AtomicBool,
+    _phantom: PhantomData<T>,
+}
+
+/// Options for a key which can be set by [`inheritable_task_local`], such as `#[no_inherit]`. The macro collects
+/// them here because keys themselves can't be modified in a `const` context.
+#[doc(hidden)]
+pub struct KeyOptions<T> {
+    inherit: bool,
+    stacked: bool,
+    init: Option<fn() -> T>,
+}
+
+impl<T> KeyOptions<T> {
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> Self {
+        Self {
+            inherit: true,
+            stacked: false,
+            init: None,
+        }
+    }
+
+    pub const fn no_inherit(self) -> Self {
+        Self {
+            inherit: false,
+            ..self
+        }
+    }
+
+    pub const fn stacked(self) -> Self {
+        Self {
+            stacked: true,
+            ..self
+        }
+    }
+
+    pub const fn init(self, init: fn() -> T) -> Self {
+        Self {
+            init: Some(init),
+            ..self
+        }
+    }
+}
+
+impl<T: 'static> InheritableLocalKey<T> {
+    #[doc(hidden)]
+    pub const fn __new(
+        name: &'static str,
+        site: &'static str,
+        version: Option<&'static str>,
+        options: KeyOptions<T>,
+    ) -> Self {
+        Self {
+            key: key_id(name, site, version),
+            name,
+            inherit: options.inherit,
+            stacked: options.stacked,
+            init: options.init,
+            lazy_default: OnceLock::new(),
+            registered: AtomicBool::new(false),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Returns the numeric identifier of this key. This is the identifier used by [`TaskLocalInheritableTable`].
+    ///
+    /// The identifier is computed at compile time from the key's name and the place it was declared at, so it doesn't
+    /// depend on the order keys are first used in. Keys are only added to the registry the first time they're given a value, which
+    /// doesn't need any code to run at load time.
+    pub fn id(&'static self) -> u128 {
+        self.key
+    }
+
+    /// Adds this key to the registry, the first time it's given a value.
+    fn register(&'static self) {
+        if self.registered.load(Ordering::Acquire) || self.registered.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        REGISTERED_KEYS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push((self.key, self.name));
+    }
+
+    /// Returns the path of the module this key was declared in followed by its name, e.g.
+    /// `my_crate::module::NUMBER`.
+    ///
+    /// Unlike the key's numeric identifier, the name is meaningful to humans, which makes it a good fit for debug
+    /// output. Keys declared inside of a function get the path of the enclosing module, so two keys with the same name
+    /// in different functions of one module share a name, while their identifiers still differ.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// mod request {
+    ///     # use tokio_inherit_task_local::inheritable_task_local;
+    ///     inheritable_task_local! {
+    ///         pub static ID: u64;
+    ///     }
+    /// }
+    ///
+    /// assert!(request::ID.key_name().ends_with("request::ID"));
+    /// ```
+    pub fn key_name(&'static self) -> &'static str {
+        self.name
+    }
+}
+
+impl<T: Send + Sync> InheritableLocalKey<T> {
+    /// Sets a value `T` as the inheritable task-local value for the future `F`.
+    ///
+    /// `F` can be any type which implements [`IntoFuture`], such as a request builder which is awaited directly. It's
+    /// converted into a future right away, outside of the scope.
+    ///
+    /// Once this future and all of its inheriting descendants have completed, the value
+    /// will be dropped.
+    ///
+    /// Completing, returning early, or being cancelled all behave the same way: values set by nested scopes inside of
+    /// `F` are dropped first, and this value is dropped once `F` has been. Detached children which inherited the value
+    /// aren't waited on, they keep it alive instead, and it's dropped when the last of them completes.
+    ///
+    /// The value is still available while `F` is dropped, so cleanup code in a [`Drop`] impl can read it, even when
+    /// the task running `F` is cancelled.
+    ///
+    /// ### Panics
+    ///
+    /// If you poll any future returned by this method inside a call to [`with`] or
+    /// [`try_with`] then the call to `poll` will panic.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// NUMBER.scope(1, async move {
+    ///     println!("task local value: {}", NUMBER.get());
+    /// }).await;
+    /// # }
+    /// ```
+    ///
+    /// [`with`]: fn@Self::with
+    /// [`try_with`]: fn@Self::try_with
+    #[must_use = "this future must be awaited or the scope has no effect"]
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn scope<F>(
+        &'static self,
+        value: T,
+        f: F,
+    ) -> TaskLocalFuture<TaskLocalInheritableTable, F::IntoFuture>
+    where
+        F: IntoFuture,
+    {
+        #[cfg(feature = "audit")]
+        audit(self.name, std::panic::Location::caller());
+        let new_task_locals = TaskLocalInheritableTable::for_scope(self.key, self.slot(value));
+        INHERITABLE_TASK_LOCALS.scope(new_task_locals, f.into_future())
+    }
+
+    /// Sets a value `T` as the inheritable task-local value for the future `F` if `value` is `Some`. If it's `None`
+    /// then `F` runs as though no scope was entered, so the inheritable task-local keeps whatever value it already had.
+    ///
+    /// This saves writing out both cases when a value is optional, which would otherwise result in two different
+    /// future types.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static USER: String;
+    /// }
+    ///
+    /// async fn handle(user: Option<String>) -> Option<String> {
+    ///     USER.scope_opt(user, async { USER.get_opt() }).await
+    /// }
+    ///
+    /// assert_eq!(handle(Some(String::from("ferris"))).await.as_deref(), Some("ferris"));
+    /// assert_eq!(handle(None).await, None);
+    /// # }
+    /// ```
+    pub fn scope_opt<F>(&'static self, value: Option<T>, f: F) -> ScopeOptFuture<F::IntoFuture>
+    where
+        F: IntoFuture,
+    {
+        let f = f.into_future();
+        let new_task_locals =
+            value.map(|value| TaskLocalInheritableTable::for_scope(self.key, self.slot(value)));
+        ScopeOptFuture::new(MaybeScoped::new(new_task_locals, f))
+    }
+
+    /// Like [`scope`](Self::scope), but the value is made by calling `make` when the returned future is first polled.
+    /// If the future is dropped without being polled, `make` is never called.
+    ///
+    /// Unlike [`scope`](Self::scope), the other inheritable task local values available to `F` are the ones available
+    /// where the returned future is first polled, not where `scope_lazy` was called. `make` runs before the scope is
+    /// entered, so it sees those values too.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static REPORT: String;
+    /// }
+    ///
+    /// let future = REPORT.scope_lazy(|| String::from("expensive"), async { REPORT.get() });
+    /// assert_eq!(future.await, "expensive");
+    /// # }
+    /// ```
+    pub fn scope_lazy<G, F>(&'static self, make: G, f: F) -> ScopeLazyFuture<T, G, F::IntoFuture>
+    where
+        G: FnOnce() -> T,
+        F: IntoFuture,
+    {
+        ScopeLazyFuture {
+            state: ScopeLazyState::Unpolled {
+                key: self,
+                make: Some(make),
+                future: Some(f.into_future()),
+            },
+        }
+    }
+
+    /// Like [`scope`](Self::scope), but the table the future runs with can be taken back out of it with
+    /// [`ScopedFuture::into_table`] once it's done. This is useful for custom executors which run several futures
+    /// with the same values, since reusing the table avoids building it again. The returned future is boxed.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// let mut first = NUMBER.scope_owned(1, async { NUMBER.get() });
+    /// assert_eq!((&mut first).await, 1);
+    /// let table = first.into_table();
+    /// assert_eq!(table.enter_scope(async { NUMBER.get() }).await, 1);
+    /// # }
+    /// ```
+    pub fn scope_owned<F>(&'static self, value: T, f: F) -> ScopedFuture<F::IntoFuture>
+    where
+        F: IntoFuture,
+    {
+        let new_task_locals = TaskLocalInheritableTable::for_scope(self.key, self.slot(value));
+        ScopedFuture::new(new_task_locals, f.into_future())
+    }
+
+    /// Runs the future `F` to completion with `value` set as the inheritable task-local value, on a new
+    /// [`current_thread`] runtime which is created for it.
+    ///
+    /// The future returned by [`scope`](Self::scope) does nothing until it's awaited, so this is a convenience for
+    /// scripts and tests which aren't already running inside an async context.
+    ///
+    /// ### Panics
+    ///
+    /// This method panics if it's called from inside a tokio runtime, since blocking there would prevent other tasks
+    /// from making progress. Use [`scope`](Self::scope) and `.await` it instead.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// fn main() {
+    ///     let output = NUMBER.block_on_scope(5, async { NUMBER.get() });
+    ///     assert_eq!(output, 5);
+    /// }
+    /// ```
+    ///
+    /// [`current_thread`]: fn@tokio::runtime::Builder::new_current_thread
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn block_on_scope<F>(&'static self, value: T, f: F) -> F::Output
+    where
+        F: IntoFuture,
+    {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            panic!("block_on_scope can't be called from inside a tokio runtime, use scope(..).await instead");
+        }
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to build a tokio runtime")
+            .block_on(self.scope(value, f))
+    }
+
+    /// Like [`scope`](Self::scope), but also instruments the future with a new `inheritable_scope` span. The span
+    /// records the name of this key in its `key` field, and the [`Debug`] representation of the value in its `value`
+    /// field.
+    ///
+    /// The span is created inside of the scope, so a [`SpanContextLayer`] restores the value whenever it's entered.
+    ///
+    /// Requires the `tracing` feature.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static REQUEST_ID: u64;
+    /// }
+    ///
+    /// REQUEST_ID.scope_in_span(7, async {
+    ///     tracing::info!("handling request");
+    /// }).await;
+    /// # }
+    /// ```
+    ///
+    /// [`SpanContextLayer`]: https://docs.rs/tokio-inherit-task-local/latest/tokio_inherit_task_local/struct.SpanContextLayer.html
+    #[cfg(feature = "tracing")]
+    #[must_use = "this future must be awaited or the scope has no effect"]
+    pub fn scope_in_span<F>(
+        &'static self,
+        value: T,
+        f: F,
+    ) -> tracing::instrument::Instrumented<TaskLocalFuture<TaskLocalInheritableTable, F::IntoFuture>>
+    where
+        T: Debug,
+        F: IntoFuture,
+    {
+        let f = f.into_future();
+        let new_task_locals = TaskLocalInheritableTable::for_scope(self.key, self.slot(value));
+        let span = enter_sync_scope(new_task_locals.clone(), || {
+            self.with(
+                |value| tracing::info_span!("inheritable_scope", key = self.name, value = ?value),
+            )
+        });
+        tracing::Instrument::instrument(INHERITABLE_TASK_LOCALS.scope(new_task_locals, f), span)
+    }
+
+    /// Like [`scope`](Self::scope), but boxes the resulting [`Future`]. This erases the type of `F`, which is useful
+    /// for storing scoped futures in a struct or collection.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use std::{future::Future, pin::Pin};
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// let futures: Vec<Pin<Box<dyn Future<Output = u32> + Send>>> = vec![
+    ///     NUMBER.scope_boxed(1, async { NUMBER.get() }),
+    ///     NUMBER.scope_boxed(2, async { NUMBER.get() * 10 }),
+    /// ];
+    /// let mut outputs = Vec::new();
+    /// for future in futures {
+    ///     outputs.push(future.await);
+    /// }
+    /// assert_eq!(outputs, [1, 20]);
+    /// # }
+    /// ```
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn scope_boxed<F>(
+        &'static self,
+        value: T,
+        f: F,
+    ) -> Pin<Box<dyn Future<Output = F::Output> + Send>>
+    where
+        F: IntoFuture,
+        F::IntoFuture: Send + 'static,
+    {
+        Box::pin(self.scope(value, f))
+    }
+
+    /// Sets a value `T` as the inheritable task-local value for the future `F`, then returns the output of `F` along
+    /// with the value.
+    ///
+    /// The value returned is the one which was set when `F` completed, so it reflects any calls to
+    /// [`update`](InheritableLocalKey::update) made in this scope. If it was removed by [`take`](Self::take) the
+    /// original value is returned instead. Inheriting descendants may still hold references to the value.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use std::sync::atomic::{AtomicU32, Ordering};
+    /// # use tokio_inherit_task_local::{inheritable_task_local, FutureInheritTaskLocal as _};
+    /// inheritable_task_local! {
+    ///     static COUNTER: AtomicU32;
+    /// }
+    ///
+    /// let ((), counter) = COUNTER.scope_returning(AtomicU32::new(0), async move {
+    ///     let child = async { COUNTER.with(|c| c.fetch_add(1, Ordering::Relaxed)) };
+    ///     tokio::spawn(child.inherit_task_local()).await.unwrap();
+    /// }).await;
+    /// assert_eq!(counter.load(Ordering::Relaxed), 1);
+    /// # }
+    /// ```
+    pub fn scope_returning<F>(
+        &'static self,
+        value: T,
+        f: F,
+    ) -> impl Future<Output = (F::Output, Arc<T>)>
+    where
+        F: IntoFuture,
+    {
+        let f = f.into_future();
+        let slot = self.slot(value);
+        let original = slot.value.clone();
+        let new_task_locals = TaskLocalInheritableTable::for_scope(self.key, slot);
+        INHERITABLE_TASK_LOCALS.scope(new_task_locals, async move {
+            let output = f.await;
+            let value = INHERITABLE_TASK_LOCALS
+                .try_with(|task_locals| task_locals.get_raw(self.key))
+                .ok()
+                .flatten()
+                .unwrap_or(original);
+            (
+                output,
+                downcast_arc(value).unwrap_or_else(|e| access_panic(e)),
+            )
+        })
+    }
+
+    /// Sets a value `T` as the inheritable task-local value for the future `F`, and runs `on_drop` once the value is
+    /// no longer available to any task.
+    ///
+    /// The callback runs exactly once, after `F` and every descendant which inherited the value have completed. This
+    /// is useful for running cleanup logic, such as flushing a buffer, for a type you can't add a [`Drop`] impl to.
+    /// References obtained from [`get_arc`](Self::get_arc) don't delay the callback. If the value is replaced by
+    /// [`update`](Self::update) the callback runs once the original value is no longer available.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::{inheritable_task_local, FutureInheritTaskLocal as _};
+    /// inheritable_task_local! {
+    ///     static LOG_PREFIX: String;
+    /// }
+    ///
+    /// LOG_PREFIX.scope_with_cleanup(String::from("request"), || println!("flushing logs"), async {
+    ///     tokio::spawn(async { println!("{}: child", LOG_PREFIX.get()) }.inherit_task_local())
+    ///         .await
+    ///         .unwrap();
+    /// }).await;
+    /// # }
+    /// ```
+    #[must_use = "this future must be awaited or the scope has no effect"]
+    pub fn scope_with_cleanup<C, F>(
+        &'static self,
+        value: T,
+        on_drop: C,
+        f: F,
+    ) -> TaskLocalFuture<TaskLocalInheritableTable, F::IntoFuture>
+    where
+        C: FnOnce() + Send + 'static,
+        F: IntoFuture,
+    {
+        let new_task_locals = TaskLocalInheritableTable::for_scope(
+            self.key,
+            Slot {
+                _cleanup: Some(Arc::new(CleanupGuard(Mutex::new(Some(Box::new(on_drop)))))),
+                ..self.slot(value)
+            },
+        );
+        INHERITABLE_TASK_LOCALS.scope(new_task_locals, f.into_future())
+    }
+
+    /// Builds a table containing the current inheritable task local values, with this one set to `value`, which can be
+    /// used to spawn any number of tasks later. This is cheaper than entering a new scope for each of them, since the
+    /// table is only built once and every task shares it.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static JOB: u32;
+    /// }
+    ///
+    /// let shared = JOB.scope_shared(1);
+    /// let handles = (0..3).map(|_| shared.spawn(async { JOB.get() })).collect::<Vec<_>>();
+    /// for handle in handles {
+    ///     assert_eq!(handle.await.unwrap(), 1);
+    /// }
+    /// # }
+    /// ```
+    pub fn scope_shared(&'static self, value: T) -> SharedScope {
+        let task_locals = inherited_task_local_table();
+        task_locals.insert(self.key, self.slot(value));
+        SharedScope {
+            task_locals: task_locals.share(),
+        }
+    }
+
+    /// Sets a value `T` as the inheritable task-local value for the future `F`, and returns a [`DescendantCounter`]
+    /// which reports roughly how many tasks are still holding on to it.
+    ///
+    /// This is meant for hunting down tasks which keep a value alive for longer than expected. See
+    /// [`DescendantCounter::count`] for the caveats.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::{inheritable_task_local, FutureInheritTaskLocal as _};
+    /// inheritable_task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// let (future, counter) = NUMBER.scope_counted(1, async {
+    ///     tokio::spawn(std::future::pending::<()>().inherit_task_local());
+    /// });
+    /// future.await;
+    /// println!("{} tasks are still holding NUMBER", counter.count());
+    /// # }
+    /// ```
+    pub fn scope_counted<F>(
+        &'static self,
+        value: T,
+        f: F,
+    ) -> (
+        TaskLocalFuture<TaskLocalInheritableTable, F::IntoFuture>,
+        DescendantCounter,
+    )
+    where
+        F: IntoFuture,
+    {
+        let slot = self.slot(value);
+        let counter_value = Arc::downgrade(&slot.value);
+        let new_task_locals = TaskLocalInheritableTable::for_scope(self.key, slot);
+        let counter = DescendantCounter {
+            map: Arc::downgrade(&new_task_locals.share()),
+            value: counter_value,
+        };
+        (
+            INHERITABLE_TASK_LOCALS.scope(new_task_locals, f.into_future()),
+            counter,
+        )
+    }
+
+    /// Sets the value returned by `update` as the inheritable task-local value for the future `F`. `update` receives
+    /// the value which is currently available, or `None` if there isn't one.
+    ///
+    /// This is useful for values such as collections, where a nested scope wants to add to what it inherited rather
+    /// than replace it. Children of `F` which inherit the value will see the one `update` returned, while the current
+    /// value is left unchanged for everything outside of `F`.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static PATH: Vec<&'static str>;
+    /// }
+    ///
+    /// let append = |segment| move |path: Option<&Vec<_>>| {
+    ///     let mut path = path.cloned().unwrap_or_default();
+    ///     path.push(segment);
+    ///     path
+    /// };
+    /// PATH.scope_with(append("api"), async {
+    ///     PATH.scope_with(append("users"), async {
+    ///         assert_eq!(PATH.get(), ["api", "users"]);
+    ///     }).await;
+    ///     assert_eq!(PATH.get(), ["api"]);
+    /// }).await;
+    /// # }
+    /// ```
+    #[must_use = "this future must be awaited or the scope has no effect"]
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn scope_with<F, G>(
+        &'static self,
+        update: G,
+        f: F,
+    ) -> TaskLocalFuture<TaskLocalInheritableTable, F::IntoFuture>
+    where
+        F: IntoFuture,
+        G: FnOnce(Option<&T>) -> T,
+    {
+        let current = self.try_get_arc().ok();
+        self.scope(update(current.as_deref()), f)
+    }
+
+    /// Awaits `init`, then sets its output as the inheritable task-local value for the future `F`.
+    ///
+    /// This is a shorthand for `KEY.scope(init.await, f)`. Note that `init` runs before the scope is established, so
+    /// it sees whatever value this task-local already had rather than the one it produces.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static USER: String;
+    /// }
+    ///
+    /// async fn fetch_user() -> String {
+    ///     tokio::task::yield_now().await;
+    ///     String::from("ferris")
+    /// }
+    ///
+    /// let greeting = USER.scope_async_init(fetch_user(), async {
+    ///     format!("hello, {}", USER.get())
+    /// }).await;
+    /// assert_eq!(greeting, "hello, ferris");
+    /// # }
+    /// ```
+    pub async fn scope_async_init<I, F>(&'static self, init: I, f: F) -> F::Output
+    where
+        I: Future<Output = T>,
+        F: IntoFuture,
+    {
+        self.scope(init.await, f).await
+    }
+
+    /// Sets a value `T` as the inheritable task-local value for the future `F`, and catches any panic raised while
+    /// polling it.
+    ///
+    /// Returns `Err` with the panic payload if `f` panicked. Either way the scope has been exited by the time this
+    /// returns, so the values available to the caller are exactly the ones it had before. This is useful for
+    /// executors or supervisors which keep running after a future they're driving panics.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// NUMBER.scope(1, async {
+    ///     let result = NUMBER.scope_catch_unwind(2, async {
+    ///         assert_eq!(NUMBER.get(), 2);
+    ///         panic!("oh no");
+    ///     }).await;
+    ///     assert!(result.is_err());
+    ///     assert_eq!(NUMBER.get(), 1);
+    /// }).await;
+    /// # }
+    /// ```
+    pub async fn scope_catch_unwind<F>(
+        &'static self,
+        value: T,
+        f: F,
+    ) -> std::thread::Result<F::Output>
+    where
+        F: IntoFuture,
+    {
+        let mut scoped = std::pin::pin!(self.scope(value, f));
+        std::future::poll_fn(|cx| {
+            match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                scoped.as_mut().poll(cx)
+            })) {
+                Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+                Ok(Poll::Pending) => Poll::Pending,
+                Err(payload) => Poll::Ready(Err(payload)),
+            }
+        })
+        .await
+    }
+
+    /// Sets a value `T` as the inheritable task-local value for the closure `F`.
+    ///
+    /// On completion of `sync_scope`, the task-local will be dropped, unless the closure
+    /// spawned a task which inherited this value.
+    ///
+    /// ### Panics
+    ///
+    /// This method panics if called inside a call to [`with`] or [`try_with`]
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// NUMBER.sync_scope(1, || {
+    ///     println!("task local value: {}", NUMBER.get());
+    /// });
+    /// # }
+    /// ```
+    ///
+    /// [`with`]: fn@Self::with
+    /// [`try_with`]: fn@Self::try_with
+    pub fn sync_scope<F, R>(&'static self, value: T, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let new_task_locals = TaskLocalInheritableTable::for_scope(self.key, self.slot(value));
+        enter_sync_scope(new_task_locals, f)
+    }
+
+    /// Sets a value `T` as the inheritable task-local value for the future `F`, and pins it there.
+    ///
+    /// For the duration of `f`, attempts to change the value of this task-local fail, including those made by inheriting
+    /// descendants. Nested calls to [`scope`] and [`sync_scope`] are ignored, while [`update`] and [`take`] return
+    /// [`Locked`](InheritableAccessError::Locked). This is useful for tests which need to force a value upon code that
+    /// sets its own.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static REQUEST_ID: u64;
+    /// }
+    ///
+    /// async fn handle_request() -> u64 {
+    ///     REQUEST_ID.scope(rand_id(), async { REQUEST_ID.get() }).await
+    /// }
+    /// # fn rand_id() -> u64 { 4 }
+    ///
+    /// let id = REQUEST_ID.scope_override(7, handle_request()).await;
+    /// assert_eq!(id, 7);
+    /// # }
+    /// ```
+    ///
+    /// [`scope`]: fn@Self::scope
+    /// [`sync_scope`]: fn@Self::sync_scope
+    /// [`update`]: fn@Self::update
+    /// [`take`]: fn@Self::take
+    #[must_use = "this future must be awaited or the scope has no effect"]
+    pub fn scope_override<F>(
+        &'static self,
+        value: T,
+        f: F,
+    ) -> TaskLocalFuture<TaskLocalInheritableTable, F::IntoFuture>
+    where
+        F: IntoFuture,
+    {
+        let new_task_locals = TaskLocalInheritableTable::for_scope(
+            self.key,
+            Slot {
+                locked: true,
+                ..self.slot(value)
+            },
+        );
+        INHERITABLE_TASK_LOCALS.scope(new_task_locals, f.into_future())
+    }
+
+    /// Wraps `value` in a slot for this key.
+    fn slot(&'static self, value: T) -> Slot {
+        self.slot_arc(Arc::new(value))
+    }
+
+    /// Like [`slot`](Self::slot), for a value which is already in an [`Arc`].
+    fn slot_arc(&'static self, value: Arc<T>) -> Slot {
+        self.register();
+        Slot {
+            value,
+            locked: false,
+            inherit: self.inherit,
+            _cleanup: None,
+            below: self
+                .stacked
+                .then(|| {
+                    INHERITABLE_TASK_LOCALS
+                        .try_with(|task_locals| task_locals.inner.borrow().get(&self.key).cloned())
+                        .ok()
+                        .flatten()
+                        .map(Arc::new)
+                })
+                .flatten(),
+        }
+    }
+
+    /// Like [`slot_arc`](Self::slot_arc), for a value which replaces the current one rather than shadowing it. The
+    /// stack of a `#[stacked]` key doesn't grow, the current value is dropped from it instead.
+    fn replacement_slot(&'static self, value: Arc<T>) -> Slot {
+        let mut slot = self.slot_arc(value);
+        slot.below = slot.below.and_then(|current| current.below.clone());
+        slot
+    }
+
+    /// Accesses the current inheritable task-local and runs the provided closure.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set.
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        self.try_with(f).unwrap_or_else(|e| access_panic(e))
+    }
+
+    /// Accesses the current inheritable task-local and runs the provided closure.
+    ///
+    /// If the task-local doesn't have a value set, the closure is given the [`Default`] value for `T` instead. With the
+    /// `log` feature enabled a warning is logged when that happens. This is useful where a missing value should
+    /// degrade gracefully rather than panic.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static RETRIES: u32;
+    /// }
+    ///
+    /// assert_eq!(RETRIES.with_or_warn(|&v| v), 0);
+    /// assert_eq!(RETRIES.sync_scope(3, || RETRIES.with_or_warn(|&v| v)), 3);
+    /// ```
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn with_or_warn<F, R>(&'static self, f: F) -> R
+    where
+        T: Default,
+        F: FnOnce(&T) -> R,
+    {
+        let mut f = Some(f);
+        match self.try_with(|v| (f.take().expect("closure was already called"))(v)) {
+            Ok(v) => v,
+            Err(_e) => {
+                #[cfg(feature = "log")]
+                log::warn!(
+                    "inheritable task local {} was not defined ({_e:?}), using the default value",
+                    self.name
+                );
+                (f.expect("closure was already called"))(&T::default())
+            }
+        }
+    }
+
+    /// Accesses the current inheritable task-local and runs the provided closure.
+    ///
+    /// If the task-local with the associated key is not present, this
+    /// method will return an `InheritableAccessError`. For a panicking variant,
+    /// see `with`.
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn try_with<F, R>(&'static self, f: F) -> Result<R, InheritableAccessError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        #[cfg(feature = "audit")]
+        audit(self.name, std::panic::Location::caller());
+        let mut f = Some(f);
+        let r = INHERITABLE_TASK_LOCALS.try_with(|task_locals| {
+            let task_locals = task_locals.inner.borrow();
+            let _guard = AccessGuard::enter();
+            let v = task_locals
+                .get(&self.key)
+                .ok_or(InheritableAccessError::NotInTable)?
+                .value
+                .as_ref();
+            Ok((f.take().expect("closure was already called"))(
+                downcast_value(v)?,
+            ))
+        });
+        let e = match r {
+            Ok(Ok(v)) => return Ok(v),
+            Ok(Err(e)) => e,
+            Err(_) => InheritableAccessError::NotInTokio,
+        };
+        let v = self.fallback(e)?;
+        let _guard = AccessGuard::enter();
+        Ok((f.expect("closure was already called"))(downcast_value(
+            v.as_ref(),
+        )?))
+    }
+
+    /// Accesses every value set for this inheritable task-local by the scopes enclosing the current one, and runs the
+    /// provided closure. The values are ordered from the outermost scope to the current one, so the last of them is
+    /// the one [`with`](Self::with) returns.
+    ///
+    /// Only keys declared with `#[stacked]` remember the values of outer scopes, for other keys the slice contains
+    /// the current value alone. The slice is empty if the current task doesn't have a value set, fallback values
+    /// such as the key's initializer aren't included.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     #[stacked]
+    ///     static SPAN_NAME: &'static str;
+    /// }
+    ///
+    /// SPAN_NAME.sync_scope("server", || {
+    ///     SPAN_NAME.sync_scope("request", || {
+    ///         let path = SPAN_NAME.with_stack(|names| names.iter().map(|name| **name).collect::<Vec<_>>().join("/"));
+    ///         assert_eq!(path, "server/request");
+    ///         assert_eq!(SPAN_NAME.get(), "request");
+    ///     });
+    /// });
+    /// ```
+    pub fn with_stack<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&[&T]) -> R,
+    {
+        let mut f = Some(f);
+        let r = INHERITABLE_TASK_LOCALS.try_with(|task_locals| {
+            let task_locals = task_locals.inner.borrow();
+            let _guard = AccessGuard::enter();
+            let mut stack = Vec::new();
+            let mut slot = task_locals.get(&self.key);
+            while let Some(current) = slot {
+                stack.push(
+                    downcast_value::<T>(current.value.as_ref()).unwrap_or_else(|e| access_panic(e)),
+                );
+                slot = current.below.as_deref();
+            }
+            stack.reverse();
+            (f.take().expect("closure was already called"))(&stack)
+        });
+        r.unwrap_or_else(|_| {
+            let _guard = AccessGuard::enter();
+            (f.expect("closure was already called"))(&[])
+        })
+    }
+
+    /// Consulted when no value is available from the table of the current task. Restored values are only used if
+    /// there's no table at all, a table without the value means it was never set or was taken out of the scope.
+    fn fallback(
+        &'static self,
+        e: InheritableAccessError,
+    ) -> Result<Arc<dyn Any + Send + Sync>, InheritableAccessError> {
+        if e == InheritableAccessError::NotInTokio {
+            if let Some(v) = restored_value(self.key) {
+                return Ok(v);
+            }
+        }
+        #[cfg(any(test, feature = "test-default"))]
+        if let Some(v) = TEST_DEFAULTS.with(|defaults| defaults.borrow().get(&self.key).cloned()) {
+            return Ok(v);
+        }
+        if let Some(init) = self.init {
+            self.register();
+            return Ok(self.lazy_default.get_or_init(|| Arc::new(init())).clone());
+        }
+        Err(e)
+    }
+
+    /// Like [`try_with`](Self::try_with), but returns a reference to the value rather than borrowing it.
+    fn lookup(&'static self) -> Result<Arc<dyn Any + Send + Sync>, InheritableAccessError> {
+        let r = INHERITABLE_TASK_LOCALS.try_with(|task_locals| {
+            task_locals
+                .get_raw(self.key)
+                .ok_or(InheritableAccessError::NotInTable)
+        });
+        match r {
+            Ok(Ok(v)) => Ok(v),
+            Ok(Err(e)) => self.fallback(e),
+            Err(_) => self.fallback(InheritableAccessError::NotInTokio),
+        }
+    }
+
+    /// Returns a counted reference to the current inheritable task-local value.
+    ///
+    /// Inheriting descendants receive references to the very same value, so the returned reference will be
+    /// [`Arc::ptr_eq`] with the ones they get.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use std::sync::Arc;
+    /// # use tokio_inherit_task_local::{inheritable_task_local, FutureInheritTaskLocal as _};
+    /// inheritable_task_local! {
+    ///     static CONFIG: String;
+    /// }
+    ///
+    /// CONFIG.scope(String::from("config"), async move {
+    ///     let config = CONFIG.get_arc();
+    ///     let child = tokio::spawn(async { CONFIG.get_arc() }.inherit_task_local());
+    ///     assert!(Arc::ptr_eq(&config, &child.await.unwrap()));
+    /// }).await;
+    /// # }
+    /// ```
+    pub fn get_arc(&'static self) -> Arc<T> {
+        self.try_get_arc().unwrap_or_else(|e| access_panic(e))
+    }
+
+    /// Returns a counted reference to the current inheritable task-local value.
+    ///
+    /// If the task-local with the associated key is not present, this method will return an
+    /// [`InheritableAccessError`]. For a panicking variant, see [`get_arc`](Self::get_arc).
+    pub fn try_get_arc(&'static self) -> Result<Arc<T>, InheritableAccessError> {
+        self.lookup().and_then(downcast_arc)
+    }
+
+    /// Borrows the current inheritable task-local value for as long as the returned [`Ref`] is alive, rather than only
+    /// for the duration of a closure as with [`with`](Self::with).
+    ///
+    /// The `Ref` holds a counted reference to the value, so it stays valid even if the scope which set the value
+    /// ends first. Unlike [`with`](Self::with), holding a `Ref` doesn't prevent the task local from being changed.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static NAME: String;
+    /// }
+    ///
+    /// NAME.sync_scope(String::from("ferris"), || {
+    ///     let name = NAME.borrow();
+    ///     assert_eq!(name.len(), 6);
+    ///     assert!(name.starts_with("fer"));
+    /// });
+    /// ```
+    pub fn borrow(&'static self) -> Ref<T> {
+        Ref {
+            value: self.get_arc(),
+        }
+    }
+
+    /// Returns `true` if `other` refers to the same allocation as the current inheritable task-local value. Returns
+    /// `false` if the task local doesn't have a value set.
+    ///
+    /// This doesn't compare the values themselves, so `T` doesn't need to implement [`PartialEq`].
+    pub fn ptr_eq_current(&'static self, other: &Arc<T>) -> bool {
+        self.lookup()
+            .is_ok_and(|v| std::ptr::addr_eq(Arc::as_ptr(&v), Arc::as_ptr(other)))
+    }
+
+    /// Accesses the current inheritable task-local and awaits the future returned by the provided closure.
+    ///
+    /// Unlike [`with`], the closure receives a counted reference to the value rather than a borrow of it, so the value
+    /// can be held across `.await` points.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static NAME: String;
+    /// }
+    ///
+    /// NAME.scope(String::from("world"), async move {
+    ///     let greeting = NAME.with_async(|name| async move {
+    ///         tokio::task::yield_now().await;
+    ///         format!("hello {name}")
+    ///     }).await;
+    ///     assert_eq!(greeting, "hello world");
+    /// }).await;
+    /// # }
+    /// ```
+    ///
+    /// [`with`]: fn@Self::with
+    pub async fn with_async<F, Fut>(&'static self, f: F) -> Fut::Output
+    where
+        F: FnOnce(Arc<T>) -> Fut,
+        Fut: Future,
+    {
+        f(self.get_arc()).await
+    }
+
+    /// Extracts a projection of the current inheritable task-local value, such as one of its fields.
+    ///
+    /// This is equivalent to [`try_with`], it exists to make the intent clearer at the call site.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// struct Request {
+    ///     id: u64,
+    ///     path: String,
+    /// }
+    ///
+    /// inheritable_task_local! {
+    ///     static REQUEST: Request;
+    /// }
+    ///
+    /// let request = Request { id: 7, path: String::from("/") };
+    /// REQUEST.scope(request, async move {
+    ///     assert_eq!(REQUEST.map(|r| r.id), Ok(7));
+    /// }).await;
+    /// # }
+    /// ```
+    ///
+    /// [`try_with`]: fn@Self::try_with
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn map<F, R>(&'static self, f: F) -> Result<R, InheritableAccessError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        self.try_with(f)
+    }
+
+    /// Removes the inheritable task-local value from the current scope, returning it.
+    ///
+    /// Afterwards [`with`] and [`try_with`] will behave as though the value was never set. Tasks which already
+    /// inherited the value will continue to see it. Returns `Ok(None)` if the value wasn't set, and
+    /// [`Locked`](InheritableAccessError::Locked) if it was pinned by [`scope_override`](Self::scope_override).
+    ///
+    /// # Panics
+    ///
+    /// This method panics if called inside a call to [`with`] or [`try_with`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static TOKEN: String;
+    /// }
+    ///
+    /// TOKEN.scope(String::from("secret"), async move {
+    ///     let token = TOKEN.take().unwrap().unwrap();
+    ///     assert_eq!(*token, "secret");
+    ///     assert!(TOKEN.try_with(|_| ()).is_err());
+    /// }).await;
+    /// # }
+    /// ```
+    ///
+    /// [`with`]: fn@Self::with
+    /// [`try_with`]: fn@Self::try_with
+    pub fn take(&'static self) -> Result<Option<Arc<T>>, InheritableAccessError> {
+        assert_not_accessing("cannot modify an inheritable task local while inside with/try_with");
+        INHERITABLE_TASK_LOCALS
+            .try_with(|task_locals| {
+                if task_locals.is_locked(self.key) {
+                    return Err(InheritableAccessError::Locked);
+                }
+                Ok(task_locals.remove(self.key))
+            })
+            .map_err(|_| InheritableAccessError::NotInTokio)??
+            .map(downcast_arc)
+            .transpose()
+    }
+
+    /// Returns a counted reference to the current inheritable task-local value. If the current task doesn't have a
+    /// value set, one is created by calling `f` and stored in the current scope first, so later reads and tasks which
+    /// inherit afterwards see it too.
+    ///
+    /// Returns [`NotInTokio`](InheritableAccessError::NotInTokio) if there's no scope to store the value in. Fallback
+    /// values, such as the key's initializer, aren't considered, `f` is called instead.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if called inside a call to [`with`] or [`try_with`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::{inheritable_task_local, scope_empty};
+    /// inheritable_task_local! {
+    ///     static CACHE: Vec<u32>;
+    /// }
+    ///
+    /// scope_empty(async {
+    ///     let cache = CACHE.get_or_insert_with(|| vec![1, 2, 3]).unwrap();
+    ///     assert_eq!(*cache, [1, 2, 3]);
+    ///     assert_eq!(CACHE.get(), [1, 2, 3]);
+    /// }).await;
+    /// # }
+    /// ```
+    ///
+    /// [`with`]: fn@Self::with
+    /// [`try_with`]: fn@Self::try_with
+    pub fn get_or_insert_with<F>(&'static self, f: F) -> Result<Arc<T>, InheritableAccessError>
+    where
+        F: FnOnce() -> T,
+    {
+        assert_not_accessing("cannot modify an inheritable task local while inside with/try_with");
+        let current = INHERITABLE_TASK_LOCALS
+            .try_with(|task_locals| task_locals.get_raw(self.key))
+            .map_err(|_| InheritableAccessError::NotInTokio)?;
+        if let Some(value) = current {
+            return downcast_arc(value);
+        }
+        // Called outside of `try_with`, so `f` can establish scopes of its own.
+        let slot = self.slot(f());
+        let value = slot.value.clone();
+        INHERITABLE_TASK_LOCALS
+            .try_with(|task_locals| task_locals.insert(self.key, slot))
+            .map_err(|_| InheritableAccessError::NotInTokio)?;
+        downcast_arc(value)
+    }
+
+    /// Sets this inheritable task-local to `value` for the current scope until the returned guard is dropped, which
+    /// restores the value it replaced. Pushing several values stacks them, and code reads whichever was pushed last.
+    ///
+    /// This is the imperative counterpart of nesting [`scope`] or [`sync_scope`] calls. Guards should be dropped in
+    /// the reverse order they were created in, inside the same scope. A guard only restores the previous value if the
+    /// one it pushed is still current, otherwise it leaves the value alone, and panics in debug builds. Pushing onto
+    /// a key pinned by [`scope_override`](Self::scope_override) has no effect.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if called outside of an inheritable scope, or inside a call to [`with`] or [`try_with`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static DEPTH: u32;
+    /// }
+    ///
+    /// DEPTH.sync_scope(0, || {
+    ///     let outer = DEPTH.push(1);
+    ///     let inner = DEPTH.push(2);
+    ///     assert_eq!(DEPTH.get(), 2);
+    ///     drop(inner);
+    ///     assert_eq!(DEPTH.get(), 1);
+    ///     drop(outer);
+    ///     assert_eq!(DEPTH.get(), 0);
+    /// });
+    /// ```
+    ///
+    /// [`with`]: fn@Self::with
+    /// [`try_with`]: fn@Self::try_with
+    /// [`scope`]: fn@Self::scope
+    /// [`sync_scope`]: fn@Self::sync_scope
+    pub fn push(&'static self, value: T) -> PopGuard {
+        assert_not_accessing("cannot modify an inheritable task local while inside with/try_with");
+        let slot = self.slot(value);
+        let pushed = Arc::as_ptr(&slot.value).cast::<()>();
+        let previous = INHERITABLE_TASK_LOCALS
+            .try_with(|task_locals| {
+                task_locals.observe_shadow(self.key);
+                task_locals.replace(self.key, slot)
+            })
+            .expect("`push` must be called inside of an inheritable scope");
+        PopGuard {
+            key: self.key,
+            previous,
+            pushed,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Replaces the inheritable task-local value with `value`, and returns the value it replaced. Unlike
+    /// [`update`](Self::update), the [`Arc`] is stored as is, so the new value is [`Arc::ptr_eq`] with `value`.
+    ///
+    /// This only affects the current scope. Tasks which already inherited the value will continue to see the old
+    /// value, while tasks which inherit after this call will see the new one. Returns `Ok(None)` if the value wasn't
+    /// set. Values pinned by [`scope_override`](Self::scope_override) are left unchanged, and
+    /// [`Locked`](InheritableAccessError::Locked) is returned.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if called inside a call to [`with`] or [`try_with`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use std::sync::Arc;
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static CONFIG: String;
+    /// }
+    ///
+    /// let reloaded = Arc::new(String::from("new"));
+    /// CONFIG.sync_scope(String::from("old"), || {
+    ///     let old = CONFIG.replace_arc(reloaded.clone()).unwrap().unwrap();
+    ///     assert_eq!(*old, "old");
+    ///     assert!(Arc::ptr_eq(&CONFIG.get_arc(), &reloaded));
+    /// });
+    /// ```
+    ///
+    /// [`with`]: fn@Self::with
+    /// [`try_with`]: fn@Self::try_with
+    pub fn replace_arc(
+        &'static self,
+        value: Arc<T>,
+    ) -> Result<Option<Arc<T>>, InheritableAccessError> {
+        assert_not_accessing("cannot modify an inheritable task local while inside with/try_with");
+        let slot = self.replacement_slot(value);
+        INHERITABLE_TASK_LOCALS
+            .try_with(|task_locals| task_locals.replace(self.key, slot))
+            .map_err(|_| InheritableAccessError::NotInTokio)?
+            .ok_or(InheritableAccessError::Locked)?
+            .map(|previous| downcast_arc(previous.value))
+            .transpose()
+    }
+
+    /// Sets a fallback value for this inheritable task-local on the current thread. The fallback is used by [`with`]
+    /// and [`try_with`] whenever no value is available from the current task, so tests don't need to establish a
+    /// scope.
+    ///
+    /// Values set by [`scope`] and [`sync_scope`] still take precedence over the fallback.
+    ///
+    /// Requires the `test-default` feature.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// NUMBER.set_test_default(1);
+    /// assert_eq!(NUMBER.get(), 1);
+    /// ```
+    ///
+    /// [`with`]: fn@Self::with
+    /// [`try_with`]: fn@Self::try_with
+    /// [`scope`]: fn@Self::scope
+    /// [`sync_scope`]: fn@Self::sync_scope
+    #[cfg(any(test, feature = "test-default"))]
+    pub fn set_test_default(&'static self, value: T) {
+        TEST_DEFAULTS.with(|defaults| {
+            defaults
+                .borrow_mut()
+                .insert(self.key, Arc::new(value) as Arc<_>)
+        });
+    }
+
+    /// Returns `true` if the inheritable task-local currently has a value set.
+    ///
+    /// Outside of any scope this reports whether the value was restored by [`CapturedContext::restore`], and returns
+    /// `false` if there are no values available at all.
+    pub fn is_set(&'static self) -> bool {
+        INHERITABLE_TASK_LOCALS
+            .try_with(|task_locals| task_locals.contains(self.key))
+            .unwrap_or_else(|_| restored_value(self.key).is_some())
+    }
+}
+
+impl<T: Clone + Send + Sync> InheritableLocalKey<T> {
+    /// Returns a copy of the inheritable task-local value
+    /// if the task-local value implements `Clone`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set.
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn get(&'static self) -> T {
+        self.with(|v| v.clone())
+    }
+
+    /// Returns a copy of the inheritable task-local value, or `None` if it doesn't have a value set.
+    ///
+    /// This is convenient for optional values. Use [`try_with`](Self::try_with) to find out why a value isn't
+    /// available.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// assert_eq!(NUMBER.get_opt(), None);
+    /// NUMBER.scope(1, async move {
+    ///     assert_eq!(NUMBER.get_opt(), Some(1));
+    /// }).await;
+    /// # }
+    /// ```
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn get_opt(&'static self) -> Option<T> {
+        self.try_with(|v| v.clone()).ok()
+    }
+
+    /// Sets a value `T` as both the inheritable task-local value, and the value of a standard [`tokio::task_local`],
+    /// for the future `F`. This is useful when code you don't control reads from a standard task local.
+    ///
+    /// Standard task locals need to own their value, so a clone of `value` is stored in `std_key`. The standard
+    /// task local is not inherited.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// tokio::task_local! {
+    ///     static STD_NUMBER: u32;
+    /// }
+    ///
+    /// NUMBER.scope_also(&STD_NUMBER, 1, async move {
+    ///     assert_eq!(NUMBER.get(), 1);
+    ///     assert_eq!(STD_NUMBER.get(), 1);
+    /// }).await;
+    /// # }
+    /// ```
+    #[must_use = "this future must be awaited or the scope has no effect"]
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn scope_also<F>(
+        &'static self,
+        std_key: &'static tokio::task::LocalKey<T>,
+        value: T,
+        f: F,
+    ) -> TaskLocalFuture<T, TaskLocalFuture<TaskLocalInheritableTable, F::IntoFuture>>
+    where
+        F: IntoFuture,
+    {
+        std_key.scope(value.clone(), self.scope(value, f))
+    }
+
+    /// Replaces the inheritable task-local value with the result of calling `f` on a copy of the current value.
+    ///
+    /// This only affects the current scope. Tasks which already inherited the value will continue to see the old
+    /// value, while tasks which inherit after this call will see the new one. Values pinned by
+    /// [`scope_override`](InheritableLocalKey::scope_override) are left unchanged, and
+    /// [`Locked`](InheritableAccessError::Locked) is returned.
+    ///
+    /// If the task-local with the associated key is not present, this
+    /// method will return an `InheritableAccessError`. `f` isn't called in that case.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if called inside a call to [`with`] or [`try_with`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # use tokio_inherit_task_local::inheritable_task_local;
+    /// inheritable_task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// NUMBER.scope(1, async move {
+    ///     NUMBER.update(|v| v + 1).unwrap();
+    ///     assert_eq!(NUMBER.get(), 2);
+    /// }).await;
+    /// # }
+    /// ```
+    ///
+    /// [`with`]: fn@Self::with
+    /// [`try_with`]: fn@Self::try_with
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn update<F>(&'static self, f: F) -> Result<(), InheritableAccessError>
+    where
+        F: FnOnce(T) -> T,
+    {
+        assert_not_accessing("cannot modify an inheritable task local while inside with/try_with");
+        let locked = INHERITABLE_TASK_LOCALS
+            .try_with(|task_locals| task_locals.is_locked(self.key))
+            .map_err(|_| InheritableAccessError::NotInTokio)?;
+        if locked {
+            return Err(InheritableAccessError::Locked);
+        }
+        let new_value = (f)(self.try_with(|v| v.clone())?);
+        let slot = self.replacement_slot(Arc::new(new_value));
+        INHERITABLE_TASK_LOCALS
+            .try_with(|task_locals| task_locals.insert(self.key, slot))
+            .map_err(|_| InheritableAccessError::NotInTokio)
+    }
+}
+
+macro_rules! with_n {
+    ($n:literal, $with:ident, $try_with:ident, $($key:ident: $t:ident),+) => {
+        #[doc = concat!("Accesses the current values of ", $n, " inheritable task-locals and runs the provided closure.")]
+        ///
+        /// This is equivalent to nesting calls to [`InheritableLocalKey::with`], but only looks up the table for the
+        /// current task once.
+        ///
+        /// # Panics
+        ///
+        /// This function will panic if any of the task locals doesn't have a value set.
+        pub fn $with<$($t,)+ F, R>($($key: &'static InheritableLocalKey<$t>,)+ f: F) -> R
+        where
+            $($t: Send + Sync + 'static,)+
+            F: FnOnce($(&$t),+) -> R,
+        {
+            $try_with($($key,)+ f).unwrap_or_else(|e| access_panic(e))
+        }
+
+        #[doc = concat!("Accesses the current values of ", $n, " inheritable task-locals and runs the provided closure.")]
+        ///
+        /// This is equivalent to nesting calls to [`InheritableLocalKey::try_with`], but only looks up the table for
+        /// the current task once. If any of the task-locals is not present, the error for the first one missing is
+        #[doc = concat!("returned. For a panicking variant, see [`", stringify!($with), "`].")]
+        pub fn $try_with<$($t,)+ F, R>(
+            $($key: &'static InheritableLocalKey<$t>,)+
+            f: F,
+        ) -> Result<R, InheritableAccessError>
+        where
+            $($t: Send + Sync + 'static,)+
+            F: FnOnce($(&$t),+) -> R,
+        {
+            let mut f = Some(f);
+            let r = INHERITABLE_TASK_LOCALS.try_with(|task_locals| {
+                let task_locals = task_locals.inner.borrow();
+                let _guard = AccessGuard::enter();
+                $(let $key = downcast_value::<$t>(task_locals.get(&$key.key)?.value.as_ref()).ok()?;)+
+                Some((f.take().expect("closure was already called"))($($key),+))
+            });
+            if let Ok(Some(v)) = r {
+                return Ok(v);
+            }
+            // At least one of the values isn't in the table, look each of them up individually to find out why.
+            $(let $key = $key.lookup()?;)+
+            let _guard = AccessGuard::enter();
+            $(let $key = downcast_value::<$t>($key.as_ref())?;)+
+            Ok((f.expect("closure was already called"))($($key),+))
+        }
+    };
+}
+
+with_n!("two", with2, try_with2, a: A, b: B);
+with_n!("three", with3, try_with3, a: A, b: B, c: C);
+with_n!("four", with4, try_with4, a: A, b: B, c: C, d: D);
+
+/// An [`InheritableLocalKey`] with its value type erased, so keys of different types can be handled together.
+///
+/// This is implemented for every [`InheritableLocalKey`], see [`fold_keys`] for an example.
+pub trait ErasedKey {
+    /// Returns the numeric identifier of this key, see [`InheritableLocalKey::id`].
+    fn id(&'static self) -> u128;
+
+    /// Returns the current value of this key, or the reason it isn't available.
+    fn get_any(&'static self) -> Result<Arc<dyn Any + Send + Sync>, InheritableAccessError>;
+}
+
+impl<T: Send + Sync + 'static> ErasedKey for InheritableLocalKey<T> {
+    fn id(&'static self) -> u128 {
+        self.key
+    }
+
+    fn get_any(&'static self) -> Result<Arc<dyn Any + Send + Sync>, InheritableAccessError> {
+        self.lookup()
+    }
+}
+
+/// Folds the current values of several inheritable task locals, which may be of different types, into one value.
+///
+/// `f` is called with the accumulated state and the value of each key in turn, in the order they're given. Keys
+/// without a value are skipped. Values are passed as [`Any`], so `f` can downcast them to the types it expects.
+///
+/// # Example
+/// ```
+/// use std::any::Any;
+/// use tokio_inherit_task_local::{fold_keys, inheritable_task_local};
+///
+/// inheritable_task_local! {
+///     static USER: String;
+///     static ATTEMPT: u32;
+/// }
+///
+/// fn describe(summary: String, value: &dyn Any) -> String {
+///     let value = match (value.downcast_ref::<String>(), value.downcast_ref::<u32>()) {
+///         (Some(user), _) => user.clone(),
+///         (_, Some(attempt)) => attempt.to_string(),
+///         _ => String::from("?"),
+///     };
+///     summary + " " + &value
+/// }
+///
+/// let summary = USER.sync_scope(String::from("ferris"), || {
+///     fold_keys(&[&USER, &ATTEMPT], String::from("context:"), describe)
+/// });
+/// assert_eq!(summary, "context: ferris");
+/// ```
+pub fn fold_keys<S>(
+    keys: &[&'static dyn ErasedKey],
+    init: S,
+    mut f: impl FnMut(S, &dyn Any) -> S,
+) -> S {
+    keys.iter().fold(init, |state, key| match key.get_any() {
+        Ok(value) => f(state, value.as_ref()),
+        Err(_) => state,
+    })
+}
+
+/// Values are stored as [`Any`], which carries the [`TypeId`](std::any::TypeId) of the value, so a value stored
+/// under a colliding key is reported instead of being misread.
+fn downcast_value<T: 'static>(v: &(dyn Any + Send + Sync)) -> Result<&T, InheritableAccessError> {
+    v.downcast_ref::<T>()
+        .ok_or(InheritableAccessError::TypeMismatch)
+}
+
+fn downcast_arc<T: Send + Sync + 'static>(
+    v: Arc<dyn Any + Send + Sync>,
+) -> Result<Arc<T>, InheritableAccessError> {
+    v.downcast::<T>()
+        .map_err(|_| InheritableAccessError::TypeMismatch)
+}
+
+/// Panics with a message describing `e`, for the accessors which don't return an [`InheritableAccessError`].
+fn access_panic(e: InheritableAccessError) -> ! {
+    match e {
+        InheritableAccessError::TypeMismatch => {
+            panic!("inheritable task local holds a value of a different type")
+        }
+        _ => panic!("inheritable task local was not defined"),
+    }
+}
+
+#[cfg(any(test, feature = "test-default"))]
+thread_local! {
+    static TEST_DEFAULTS: RefCell<HashMap<u128, Arc<dyn Any + Send + Sync + 'static>>> = RefCell::new(HashMap::new());
+}
+
+/// How many cleared maps each thread will hold on to for reuse.
+const MAP_POOL_SIZE: usize = 32;
+
+thread_local! {
+    /// Allocations of tables that have been dropped, kept around so creating a table doesn't always need to allocate.
+    /// Every map in here is empty.
+    static MAP_POOL: RefCell<Vec<Arc<TaskLocalMap>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Returns an empty map which isn't shared, reusing an existing allocation if one is available.
+fn pooled_map() -> Arc<TaskLocalMap> {
+    MAP_POOL
+        .try_with(|pool| pool.try_borrow_mut().ok().and_then(|mut pool| pool.pop()))
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+/// Like [`pooled_map`], but makes sure the map has room for as many values as the table capacity hint asks for.
+fn hinted_pooled_map() -> Arc<TaskLocalMap> {
+    let mut map = pooled_map();
+    let hint = TABLE_CAPACITY_HINT.load(Ordering::Relaxed);
+    if map.capacity() < hint {
+        Arc::get_mut(&mut map)
+            .expect("pooled maps are never shared")
+            .reserve(hint);
+    }
+    map
+}
+
+fn new_task_local_table() -> TaskLocalInheritableTable {
+    TaskLocalInheritableTable::new(hinted_pooled_map())
+}
+
+thread_local! {
+    /// The map every table made by [`empty_task_local_table`] on this thread starts out with.
+    static EMPTY_MAP: Arc<TaskLocalMap> = Arc::default();
+}
+
+/// Returns a table without any values, which doesn't get a map of its own until a value is stored in it.
+fn empty_task_local_table() -> TaskLocalInheritableTable {
+    EMPTY_MAP
+        .try_with(|map| TaskLocalInheritableTable::new(Arc::clone(map)))
+        .unwrap_or_else(|_| new_task_local_table())
+}
+
+static TABLE_CAPACITY_HINT: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets how many values newly created tables should have room for. Tables grow as values are added to them, so this
+/// is only an optimization, which avoids reallocating a table when many of them are set in a task.
+///
+/// Only tables created without copying an existing one, such as the table for the outermost scope, are affected. By
+/// default tables start out without any room, and don't allocate until a value is added to them.
+///
+/// # Example
+/// ```
+/// use tokio_inherit_task_local::{set_table_capacity_hint, TaskLocalInheritableTable};
+///
+/// set_table_capacity_hint(16);
+/// assert!(TaskLocalInheritableTable::default().capacity() >= 16);
+/// ```
+pub fn set_table_capacity_hint(capacity: usize) {
+    TABLE_CAPACITY_HINT.store(capacity, Ordering::Relaxed);
+}
+
+thread_local! {
+    /// How many calls to `with` or `try_with` are currently running their closure on this thread.
+    static ACCESS_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Marks the current thread as accessing an inheritable task local for as long as it's alive.
+struct AccessGuard;
+
+impl AccessGuard {
+    fn enter() -> Self {
+        let _ = ACCESS_DEPTH.try_with(|depth| depth.set(depth.get() + 1));
+        Self
+    }
+}
+
+impl Drop for AccessGuard {
+    fn drop(&mut self) {
+        let _ = ACCESS_DEPTH.try_with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+/// Panics with `msg` if this thread is inside of a call to `with` or `try_with`. Catching this here gives a clearer
+/// message than the borrow panic which would otherwise come from tokio or the table.
+fn assert_not_accessing(msg: &str) {
+    if ACCESS_DEPTH
+        .try_with(|depth| depth.get() > 0)
+        .unwrap_or(false)
+    {
+        panic!("{msg}");
+    }
+}
+
+/// Makes `task_locals` available to `f`. All synchronous entry points go through here.
+fn enter_sync_scope<F, R>(task_locals: TaskLocalInheritableTable, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    assert_not_accessing("cannot enter an inheritable scope while inside with/try_with");
+    INHERITABLE_TASK_LOCALS.sync_scope(task_locals, f)
+}
+
+/// Returns a copy of the table for the current task, or an empty table if there isn't one.
+fn current_task_local_table() -> TaskLocalInheritableTable {
+    current_table().unwrap_or_else(new_task_local_table)
+}
+
+/// Like [`current_task_local_table`], but used when a new task is inheriting the values. The empty inherit hook is invoked
+/// if there's no table to inherit from.
+fn inherited_task_local_table() -> TaskLocalInheritableTable {
+    let Some(task_locals) = current_table() else {
+        let hook = *EMPTY_INHERIT_HOOK
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        hook();
+        observe(ScopeEvent::Inherit { key_count: 0 });
+        return empty_task_local_table();
+    };
+    let task_locals = without_no_inherit(task_locals);
+    observe(ScopeEvent::Inherit {
+        key_count: task_locals.inner.borrow().len(),
+    });
+    task_locals
+}
+
+/// Removes the values which shouldn't be inherited from `task_locals`.
+fn without_no_inherit(task_locals: TaskLocalInheritableTable) -> TaskLocalInheritableTable {
+    // Checked first so the map is only copied when there's something to leave out.
+    if task_locals
+        .inner
+        .borrow()
+        .values()
+        .any(|slot| !slot.inherit)
+    {
+        task_locals.map_mut().retain(|_, slot| slot.inherit);
+    }
+    task_locals
+}
+
+thread_local! {
+    /// Values restored on this thread outside of any scope, such as by [`SpanContextLayer`] when a span is entered, or
+    /// by [`CapturedContext::restore`]. The last one is the most recent, and is used whenever the current task doesn't have a value.
+    static RESTORED: RefCell<Vec<Arc<TaskLocalMap>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Drops `value`, which a closure took out of a [`RefCell`] it borrowed. Passing it here from outside the closure makes
+/// sure it's dropped after the borrow ends, since dropping values could run arbitrary code, including code which
+/// accesses inheritable task locals.
+fn drop_outside_borrow<T>(value: T) {
+    drop(value);
+}
+
+fn push_restored(task_locals: Arc<TaskLocalMap>) {
+    let _ = RESTORED.try_with(|restored| restored.borrow_mut().push(task_locals));
+}
+
+/// Removes the most recent entry for `task_locals` from the restored values, and returns `false` if something was
+/// restored after it.
+fn pop_restored(task_locals: *const TaskLocalMap) -> bool {
+    let popped = RESTORED.try_with(|restored| {
+        let mut restored = restored.borrow_mut();
+        let index = restored
+            .iter()
+            .rposition(|map| Arc::as_ptr(map) == task_locals)?;
+        let was_last = index + 1 == restored.len();
+        Some((restored.remove(index), was_last))
+    });
+    // Nothing to pop counts as in order, the thread local may already be gone.
+    let Ok(Some((task_locals, was_last))) = popped else {
+        return true;
+    };
+    drop_outside_borrow(task_locals);
+    was_last
+}
+
+/// Returns the most recently restored value for `key`, if there is one.
+fn restored_value(key: u128) -> Option<Arc<dyn Any + Send + Sync>> {
+    Some(restored_map()?.get(&key)?.value.clone())
+}
+
+fn restored_map() -> Option<Arc<TaskLocalMap>> {
+    RESTORED
+        .try_with(|restored| restored.borrow().last().cloned())
+        .ok()
+        .flatten()
+}
+
+static EMPTY_INHERIT_HOOK: RwLock<fn()> = RwLock::new(|| {});
+
+/// Something that happened to the inheritable task local values, reported to the function set by
+/// [`set_scope_observer`].
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum ScopeEvent {
+    /// A scope setting the key with this identifier was entered. The identifier of a key can be found with
+    /// [`InheritableLocalKey::id`].
+    Enter {
+        /// The identifier of the key that was set.
+        key: u128,
+    },
+    /// A copy of the current values was made for a task or closure to inherit.
+    Inherit {
+        /// How many values were inherited.
+        key_count: usize,
+    },
+    /// A scope has ended, and its values are no longer available to it. Descendants which inherited the values may
+    /// continue to use them.
+    Exit,
+}
+
+type ScopeObserver = Arc<dyn Fn(ScopeEvent) + Send + Sync>;
+
+/// `true` once an observer is set, so scopes don't need to take the lock when there isn't one.
+static HAS_SCOPE_OBSERVER: AtomicBool = AtomicBool::new(false);
+
+static SCOPE_OBSERVER: RwLock<Option<ScopeObserver>> = RwLock::new(None);
+
+/// Sets a function to be called whenever an inheritable scope is entered, inherited, or exited. This is useful for
+/// debugging cases where values aren't propagated the way you expect them to be.
+///
+/// Events are reported by [`InheritableLocalKey::scope`], [`InheritableLocalKey::sync_scope`],
+/// [`inherit_task_local`](fn@inherit_task_local), and the functions built on top of them. The observer is called on
+/// whichever thread the event happened on. Setting an observer replaces the previous one, by default there isn't one.
+///
+/// # Example
+/// ```
+/// use std::sync::{Arc, Mutex};
+/// use tokio_inherit_task_local::{inheritable_task_local, set_scope_observer, ScopeEvent};
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// let events = Arc::new(Mutex::new(Vec::new()));
+/// set_scope_observer(Box::new({
+///     let events = events.clone();
+///     move |event| events.lock().unwrap().push(event)
+/// }));
+/// NUMBER.sync_scope(1, || ());
+/// assert_eq!(*events.lock().unwrap(), [ScopeEvent::Enter { key: NUMBER.id() }, ScopeEvent::Exit]);
+/// ```
+pub fn set_scope_observer(observer: Box<dyn Fn(ScopeEvent) + Send + Sync>) {
+    *SCOPE_OBSERVER
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(observer.into());
+    HAS_SCOPE_OBSERVER.store(true, Ordering::Release);
+}
+
+/// Reports `count` scopes as exited to the scope observer.
+fn observe_exits(count: usize) {
+    for _ in 0..count {
+        observe(ScopeEvent::Exit);
+    }
+}
+
+fn observe(event: ScopeEvent) {
+    if !HAS_SCOPE_OBSERVER.load(Ordering::Acquire) {
+        return;
+    }
+    // Cloned out so the observer can replace itself without deadlocking.
+    let observer = SCOPE_OBSERVER
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone();
+    if let Some(observer) = observer {
+        observer(event);
+    }
+}
+
+#[cfg(feature = "audit")]
+type AuditHook = fn(&'static str, &'static std::panic::Location<'static>);
+
+#[cfg(feature = "audit")]
+static AUDIT_HOOK: RwLock<AuditHook> = RwLock::new(log_access);
+
+#[cfg(feature = "audit")]
+fn log_access(_name: &'static str, _location: &'static std::panic::Location<'static>) {
+    #[cfg(feature = "log")]
+    log::trace!("{_name} accessed at {_location}");
+}
+
+#[cfg(feature = "audit")]
+fn audit(name: &'static str, location: &'static std::panic::Location<'static>) {
+    let hook = *AUDIT_HOOK
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    hook(name, location);
+}
+
+/// Sets a function to be called whenever an inheritable task local is accessed with
+/// [`with`](InheritableLocalKey::with) or [`try_with`](InheritableLocalKey::try_with), or given a value with
+/// [`scope`](InheritableLocalKey::scope). The hook receives the [`key_name`](InheritableLocalKey::key_name) of the
+/// key, and the location of the code which called the method.
+///
+/// By default each access is logged at the trace level if the `log` feature is enabled, otherwise nothing happens.
+/// Setting a hook replaces the previous one. Requires the `audit` feature, without it these methods don't record
+/// their call sites at all.
+///
+/// # Example
+/// ```
+/// use tokio_inherit_task_local::{inheritable_task_local, set_audit_hook};
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// set_audit_hook(|name, location| eprintln!("{name} accessed at {location}"));
+/// NUMBER.sync_scope(1, || NUMBER.with(|_| ()));
+/// ```
+#[cfg(feature = "audit")]
+pub fn set_audit_hook(hook: AuditHook) {
+    *AUDIT_HOOK
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = hook;
+}
+
+/// Sets a function to be called whenever something tries to inherit inheritable task local values, but there are none
+/// to inherit from because it's running outside of any scope. In that case an empty table is used instead, which can
+/// mask bugs where a scope was never established. The hook can be used to log, or to assert in debug builds.
+///
+/// This affects [`inherit_task_local`](fn@inherit_task_local),
+/// [`FutureInheritTaskLocal::inherit_task_local`], and the other functions which inherit values. By default the hook
+/// does nothing. Setting a hook replaces the previous one.
+///
+/// # Example
+/// ```
+/// use tokio_inherit_task_local::{inherit_task_local, set_empty_inherit_hook};
+///
+/// set_empty_inherit_hook(|| eprintln!("inheriting outside of any inheritable scope"));
+/// let _ = inherit_task_local(|| ());
+/// ```
+pub fn set_empty_inherit_hook(hook: fn()) {
+    *EMPTY_INHERIT_HOOK
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = hook;
+}
+
+static UNPOLLED_HOOK: RwLock<fn()> = RwLock::new(warn_unpolled);
+
+fn warn_unpolled() {
+    #[cfg(feature = "log")]
+    log::warn!(
+        "an inheriting future was dropped without being polled, it may be missing an `.await`"
+    );
+}
+
+/// Calls the unpolled hook, in debug builds only.
+fn report_unpolled() {
+    if cfg!(debug_assertions) {
+        let hook = *UNPOLLED_HOOK
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        hook();
+    }
+}
+
+/// Sets a function to be called in debug builds whenever a future returned by
+/// [`inherit_task_local`](FutureInheritTaskLocal::inherit_task_local) or [`scope_opt`](InheritableLocalKey::scope_opt)
+/// is dropped without ever being polled. This usually means it was never awaited or spawned, so the values it
+/// inherited were never used.
+///
+/// By default a warning is logged if the `log` feature is enabled, otherwise nothing happens. Setting a hook replaces
+/// the previous one. The hook is never called in release builds.
+///
+/// # Example
+/// ```
+/// use tokio_inherit_task_local::{set_unpolled_hook, FutureInheritTaskLocal as _};
+///
+/// set_unpolled_hook(|| eprintln!("forgot to await an inheriting future"));
+/// drop(async {}.inherit_task_local());
+/// ```
+pub fn set_unpolled_hook(hook: fn()) {
+    *UNPOLLED_HOOK
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = hook;
+}
+
+static SHADOW_HOOK: RwLock<fn(u128)> = RwLock::new(|_| {});
+
+/// Sets a function to be called whenever a scope sets a key which already has a value, shadowing the value it had.
+/// The hook is passed the [`id`](InheritableLocalKey::id) of the key. The value is still shadowed as usual.
+///
+/// Setting the same key more than once is often a mistake, for example two layers of a middleware stack which both
+/// set a request id. The hook can be used to log these cases, or to assert in debug builds. By default the hook does
+/// nothing. Setting a hook replaces the previous one.
+///
+/// # Example
+/// ```
+/// use tokio_inherit_task_local::{inheritable_task_local, set_shadow_hook};
+///
+/// inheritable_task_local! {
+///     static REQUEST_ID: u64;
+/// }
+///
+/// set_shadow_hook(|key| debug_assert_ne!(key, REQUEST_ID.id(), "REQUEST_ID was set twice"));
+/// REQUEST_ID.sync_scope(1, || ());
+/// ```
+pub fn set_shadow_hook(hook: fn(u128)) {
+    *SHADOW_HOOK
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = hook;
+}
+
+/// The identifier and name of every key which has been given a value, in the order it first happened.
+static REGISTERED_KEYS: Mutex<Vec<(u128, &'static str)>> = Mutex::new(Vec::new());
+
+/// Returns how many inheritable task local keys have been registered. Keys register themselves the first time they're
+/// given a value, so keys which have been declared but never used aren't counted.
+///
+/// Together with [`current_occupancy`], this is useful for exporting metrics.
+pub fn registered_key_count() -> usize {
+    REGISTERED_KEYS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .len()
+}
+
+/// Returns the [`id`](InheritableLocalKey::id) and [`key_name`](InheritableLocalKey::key_name) of every inheritable
+/// task local key which has been registered, in the order they were registered in. Like [`registered_key_count`],
+/// this only includes keys which have been given a value at least once.
+///
+/// This is useful for debugging tools, such as an endpoint which lists the context known to a service.
+///
+/// # Example
+/// ```
+/// use tokio_inherit_task_local::{inheritable_task_local, registered_keys};
+///
+/// inheritable_task_local! {
+///     static REQUEST_ID: u64;
+/// }
+///
+/// REQUEST_ID.sync_scope(1, || ());
+/// assert!(registered_keys().contains(&(REQUEST_ID.id(), REQUEST_ID.key_name())));
+/// ```
+pub fn registered_keys() -> Vec<(u128, &'static str)> {
+    REGISTERED_KEYS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+}
+
+/// Returns how many inheritable task locals have a value set for the current task, or `None` if inheritable task
+/// locals aren't available to it at all.
+///
+/// # Example
+/// ```
+/// use tokio_inherit_task_local::{current_occupancy, inheritable_task_local};
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// assert_eq!(current_occupancy(), None);
+/// assert_eq!(NUMBER.sync_scope(1, current_occupancy), Some(1));
+/// ```
+pub fn current_occupancy() -> Option<usize> {
+    INHERITABLE_TASK_LOCALS
+        .try_with(|task_locals| task_locals.set_slots())
+        .ok()
+}
+
+/// Returns `true` if inheritable task locals are available to the current task, even if none of them have a value.
+///
+/// This is cheaper than calling [`try_with`](InheritableLocalKey::try_with), and can be used by library code to decide
+/// whether to read a value at all. Values restored outside of any scope, such as by [`CapturedContext::restore`],
+/// don't count.
 ///
 /// # Example
 /// ```
-/// use tokio_inherit_task_local::{inherit_task_local, inheritable_task_local};
+/// use tokio_inherit_task_local::{in_scope, inheritable_task_local};
 ///
 /// inheritable_task_local! {
 ///     static NUMBER: u32;
 /// }
 ///
-/// #[tokio::main]
-/// async fn main() {
-///     let output = NUMBER.scope(1, async move {
-///         tokio::task::spawn_blocking(inherit_task_local(|| NUMBER.get())).await.unwrap()
-///     }).await;
-///     assert_eq!(output, 1);
-/// }
+/// assert!(!in_scope());
+/// assert!(NUMBER.sync_scope(1, in_scope));
 /// ```
-pub fn inherit_task_local<F, R>(f: F) -> impl FnOnce() -> R + Send + 'static
-where
-    F: FnOnce() -> R + Send + 'static,
-{
-    let new_task_locals = INHERITABLE_TASK_LOCALS
-        .try_with(|task_locals| task_locals.clone())
-        .unwrap_or_else(|_| TaskLocalInheritableTable::new(HashMap::new()));
-    move || INHERITABLE_TASK_LOCALS.sync_scope(new_task_locals, f)
-}
-
-tokio::task_local! {
-    static INHERITABLE_TASK_LOCALS: TaskLocalInheritableTable
+pub fn in_scope() -> bool {
+    INHERITABLE_TASK_LOCALS.try_with(|_| ()).is_ok()
 }
 
-/// A key for inheritable task-local data.
+/// Returns every value set for the current task, along with the [`id`](InheritableLocalKey::id) of its key. The list
+/// is sorted by id, and is empty if inheritable task locals aren't available to the current task.
 ///
-/// This type is generated by the [`inheritable_task_local!`] macro.
-///
-/// Unlike [`std::thread::LocalKey`], `InheritableLocalKey` will
-/// _not_ lazily initialize the value on first access. Instead, the
-/// value is first initialized when the future containing
-/// the task-local is first polled by a futures executor, like Tokio.
-///
-/// # Examples
+/// This is meant for generic tools, such as one which dumps the context of a task, which don't know the types of the
+/// values. It's up to the caller to downcast them.
 ///
+/// # Example
 /// ```
-/// # async fn dox() {
-/// # use tokio_inherit_task_local::inheritable_task_local;
+/// use tokio_inherit_task_local::{dump_current, inheritable_task_local};
+///
 /// inheritable_task_local! {
 ///     static NUMBER: u32;
 /// }
 ///
-/// NUMBER.scope(1, async move {
-///     assert_eq!(NUMBER.get(), 1);
-/// }).await;
+/// let values = NUMBER.sync_scope(1, dump_current);
+/// assert_eq!(values.len(), 1);
+/// assert_eq!(values[0].0, NUMBER.id());
+/// assert_eq!(values[0].1.downcast_ref::<u32>(), Some(&1));
+/// assert!(dump_current().is_empty());
+/// ```
+pub fn dump_current() -> Vec<(u128, Arc<dyn Any + Send + Sync>)> {
+    let Some(task_locals) = current_table() else {
+        return Vec::new();
+    };
+    let mut values = task_locals
+        .inner
+        .borrow()
+        .iter()
+        .map(|(&key, slot)| (key, slot.value.clone()))
+        .collect::<Vec<_>>();
+    values.sort_unstable_by_key(|&(key, _)| key);
+    values
+}
+
+/// Returns the approximate number of bytes allocated when the table of the current task is copied, or `0` if
+/// inheritable task locals aren't available to the current task.
 ///
-/// NUMBER.scope(2, async move {
-///     assert_eq!(NUMBER.get(), 2);
+/// Inheriting doesn't copy the table, a copy is only made when a task changes a value in a table it shares with
+/// another. The estimate counts the reference counts of the table, plus the key, value pointer, and bookkeeping for
+/// each value that's set. The values themselves are shared rather than copied, so their size isn't included. This is
+/// meant for logging while deciding how many values to keep in the context.
 ///
-///     NUMBER.scope(3, async move {
-///         assert_eq!(NUMBER.get(), 3);
-///     }).await;
-/// }).await;
-/// # }
+/// # Example
 /// ```
+/// use tokio_inherit_task_local::{context_clone_bytes, inheritable_task_local};
 ///
-/// [`std::thread::LocalKey`]: struct@std::thread::LocalKey
-pub struct InheritableLocalKey<T: 'static> {
-    #[doc(hidden)]
-    pub key: u128,
-    #[doc(hidden)]
-    pub _phantom: PhantomData<T>,
-}
-
-impl<T: Send + Sync> InheritableLocalKey<T> {
-    /// Sets a value `T` as the inheritable task-local value for the future `F`.
-    ///
-    /// Once this future and all of its inheriting descendants have completed, the value
-    /// will be dropped.
-    ///
-    /// ### Panics
-    ///
-    /// If you poll any future returned by this method inside a call to [`with`] or
-    /// [`try_with`] then the call to `poll` will panic.
-    ///
-    /// ### Examples
-    ///
-    /// ```
-    /// # async fn dox() {
-    /// # use tokio_inherit_task_local::inheritable_task_local;
-    /// inheritable_task_local! {
-    ///     static NUMBER: u32;
-    /// }
-    ///
-    /// NUMBER.scope(1, async move {
-    ///     println!("task local value: {}", NUMBER.get());
-    /// }).await;
-    /// # }
-    /// ```
-    ///
-    /// [`with`]: fn@Self::with
-    /// [`try_with`]: fn@Self::try_with
-    pub fn scope<F>(&'static self, value: T, f: F) -> TaskLocalFuture<TaskLocalInheritableTable, F>
-    where
-        F: Future,
-    {
-        let mut new_task_locals = INHERITABLE_TASK_LOCALS
-            .try_with(|task_locals| task_locals.clone())
-            .unwrap_or_else(|_| new_task_local_table());
-        new_task_locals
-            .inner
-            .insert(self.key, Arc::new(value) as Arc<_>);
-        INHERITABLE_TASK_LOCALS.scope(new_task_locals, f)
-    }
-
-    /// Sets a value `T` as the inheritable task-local value for the closure `F`.
-    ///
-    /// On completion of `sync_scope`, the task-local will be dropped, unless the closure
-    /// spawned a task which inherited this value.
-    ///
-    /// ### Panics
-    ///
-    /// This method panics if called inside a call to [`with`] or [`try_with`]
-    ///
-    /// ### Examples
-    ///
-    /// ```
-    /// # async fn dox() {
-    /// # use tokio_inherit_task_local::inheritable_task_local;
-    /// inheritable_task_local! {
-    ///     static NUMBER: u32;
-    /// }
-    ///
-    /// NUMBER.sync_scope(1, || {
-    ///     println!("task local value: {}", NUMBER.get());
-    /// });
-    /// # }
-    /// ```
-    ///
-    /// [`with`]: fn@Self::with
-    /// [`try_with`]: fn@Self::try_with
-    pub fn sync_scope<F, R>(&'static self, value: T, f: F) -> R
-    where
-        F: FnOnce() -> R,
-    {
-        let mut new_task_locals = INHERITABLE_TASK_LOCALS
-            .try_with(|task_locals| task_locals.clone())
-            .unwrap_or_else(|_| new_task_local_table());
-        new_task_locals
-            .inner
-            .insert(self.key, Arc::new(value) as Arc<_>);
-        INHERITABLE_TASK_LOCALS.sync_scope(new_task_locals, f)
-    }
-
-    /// Accesses the current inheritable task-local and runs the provided closure.
-    ///
-    /// # Panics
-    ///
-    /// This function will panic if the task local doesn't have a value set.
-    pub fn with<F, R>(&'static self, f: F) -> R
-    where
-        F: FnOnce(&T) -> R,
-    {
-        INHERITABLE_TASK_LOCALS.with(|task_locals| {
-            let v = task_locals
-                .inner
-                .get(&self.key)
-                .expect("inheritable task local was not defined")
-                .as_ref();
-            (f)(v
-                .downcast_ref::<T>()
-                .expect("internal was not of correct type, this is a tokio-inherit-task-local bug"))
-        })
-    }
-
-    /// Accesses the current inheritable task-local and runs the provided closure.
-    ///
-    /// If the task-local with the associated key is not present, this
-    /// method will return an `InheritableAccessError`. For a panicking variant,
-    /// see `with`.
-    pub fn try_with<F, R>(&'static self, f: F) -> Result<R, InheritableAccessError>
-    where
-        F: FnOnce(&T) -> R,
-    {
-        let r = INHERITABLE_TASK_LOCALS.try_with(|task_locals| {
-            let v = task_locals
-                .inner
-                .get(&self.key)
-                .ok_or(InheritableAccessError::NotInTable)?
-                .as_ref();
-            Ok((f)(v.downcast_ref::<T>().expect(
-                "internal was not of correct type, this is a tokio-inherit-task-local bug",
-            )))
-        });
-        match r {
-            Ok(Ok(v)) => Ok(v),
-            Ok(Err(e)) => Err(e),
-            Err(_) => Err(InheritableAccessError::NotInTokio),
-        }
-    }
-}
-
-impl<T: Clone + Send + Sync> InheritableLocalKey<T> {
-    /// Returns a copy of the inheritable task-local value
-    /// if the task-local value implements `Clone`.
-    ///
-    /// # Panics
-    ///
-    /// This function will panic if the task local doesn't have a value set.
-    pub fn get(&'static self) -> T {
-        self.with(|v| v.clone())
-    }
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+///     static NAME: String;
+/// }
+///
+/// let one = NUMBER.sync_scope(1, context_clone_bytes);
+/// let two = NUMBER.sync_scope(1, || NAME.sync_scope(String::new(), context_clone_bytes));
+/// assert!(one < two);
+/// assert_eq!(context_clone_bytes(), 0);
+/// ```
+pub fn context_clone_bytes() -> usize {
+    let Some(task_locals) = current_table() else {
+        return 0;
+    };
+    let len = task_locals.inner.borrow().len();
+    // The strong and weak counts of the `Arc`, then the map itself.
+    let header = 2 * std::mem::size_of::<usize>() + std::mem::size_of::<TaskLocalMap>();
+    // Each entry is stored next to a control byte.
+    let entry = std::mem::size_of::<(u128, Slot)>() + 1;
+    header + len * entry
 }
 
-fn new_task_local_table() -> TaskLocalInheritableTable {
-    TaskLocalInheritableTable::new(HashMap::new())
+/// Runs `f` with the value set for the key with the given [`id`](InheritableLocalKey::id) in the current task, or
+/// `None` if it doesn't have one.
+///
+/// This is the counterpart to [`dump_current`] for reading a single value, for code which doesn't know its type. It's
+/// up to `f` to downcast it. Values from initializers and test defaults aren't seen, only ones set by a scope.
+///
+/// # Example
+/// ```
+/// use tokio_inherit_task_local::{inheritable_task_local, with_raw};
+///
+/// inheritable_task_local! {
+///     static NUMBER: u32;
+/// }
+///
+/// let read = || with_raw(NUMBER.id(), |v| v.and_then(|v| v.downcast_ref::<u32>()).copied());
+/// assert_eq!(NUMBER.sync_scope(1, read), Some(1));
+/// assert_eq!(read(), None);
+/// ```
+pub fn with_raw<R>(key: u128, f: impl FnOnce(Option<&(dyn Any + Send + Sync)>) -> R) -> R {
+    let value = INHERITABLE_TASK_LOCALS
+        .try_with(|task_locals| task_locals.get_raw(key))
+        .unwrap_or_else(|_| restored_value(key));
+    let _guard = AccessGuard::enter();
+    f(value.as_deref())
 }
 
 /// Returned when the requested inheritable task local did not have a value set.
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub enum InheritableAccessError {
     /// Inheritable task locals are available to this future, however this key doesn't have a corresponding value.
+    ///
+    /// This includes keys which were never set by any scope, even if the table was inherited long before the key was
+    /// first used. Tables only hold the keys that were set, so there's no such thing as a key the table is too small
+    /// for.
     NotInTable,
     /// Inheritable task locals are not initialized for this future at all.
     NotInTokio,
+    /// The value stored for this key isn't of the key's type. This can only happen if two keys ended up with the same
+    /// [`id`](InheritableLocalKey::id), which takes two keys with the same name declared at the same site of the same
+    /// crate version, or a hash collision.
+    TypeMismatch,
+    /// The value can't be changed, because it was pinned by [`scope_override`](InheritableLocalKey::scope_override).
+    Locked,
 }
 
 /// Declares a new inheritable task-local key of type [`InheritableLocalKey`].
@@ -337,6 +4147,78 @@ pub enum InheritableAccessError {
 /// # fn main() {}
 /// ```
 ///
+/// # Initializers
+///
+/// A declaration can be given an initializer, which produces the value used whenever the key doesn't have one set.
+/// The initializer is called at most once, the first time it's needed, and the value it returns is shared from then
+/// on. Values set by [`scope`](InheritableLocalKey::scope) still take precedence over it. Initializers can't capture
+/// anything, since they're stored as function pointers.
+///
+/// ```
+/// # use tokio_inherit_task_local::inheritable_task_local;
+/// inheritable_task_local! {
+///     static GREETING: String = || String::from("hello");
+/// }
+///
+/// assert_eq!(GREETING.get(), "hello");
+/// assert_eq!(GREETING.sync_scope(String::from("hi"), || GREETING.get()), "hi");
+/// ```
+///
+/// # Attributes
+///
+/// A declaration marked with `#[no_inherit]` can be read in the task which set it, but it's never inherited by child
+/// tasks. This is useful for sensitive values, such as credentials.
+///
+/// ```
+/// # async fn dox() {
+/// # use tokio_inherit_task_local::{inheritable_task_local, FutureInheritTaskLocal as _};
+/// inheritable_task_local! {
+///     #[no_inherit]
+///     static AUTH_TOKEN: String;
+/// }
+///
+/// AUTH_TOKEN.scope(String::from("secret"), async {
+///     assert!(AUTH_TOKEN.is_set());
+///     let child = tokio::spawn(async { AUTH_TOKEN.is_set() }.inherit_task_local());
+///     assert!(!child.await.unwrap());
+/// }).await;
+/// # }
+/// ```
+///
+/// A declaration marked with `#[stacked]` remembers the values set by outer scopes, which can be read with
+/// [`with_stack`](InheritableLocalKey::with_stack). Reading it otherwise behaves the same as any other key.
+///
+/// ```
+/// # use tokio_inherit_task_local::inheritable_task_local;
+/// inheritable_task_local! {
+///     #[stacked]
+///     static DEPTH: u32;
+/// }
+///
+/// DEPTH.sync_scope(1, || {
+///     DEPTH.sync_scope(2, || assert_eq!(DEPTH.with_stack(|depths| depths.len()), 2));
+/// });
+/// ```
+///
+/// A declaration marked with `#[accessors(get, try_get)]` also generates functions with the given names in the same
+/// module, which return a clone of the current value. The type of the key must implement [`Clone`]. The functions
+/// have the same visibility as the key.
+///
+/// ```
+/// # use tokio_inherit_task_local::inheritable_task_local;
+/// inheritable_task_local! {
+///     #[accessors(user, try_user)]
+///     pub static USER: String;
+/// }
+///
+/// assert!(try_user().is_err());
+/// assert_eq!(USER.sync_scope(String::from("ferris"), user), "ferris");
+/// ```
+///
+/// Other attributes, such as doc comments and `#[cfg]`, are applied to the generated static. The
+/// [`id`](InheritableLocalKey::id) of a key is derived from its name and location rather than the order it was declared
+/// in, so leaving out a declaration with `#[cfg]` doesn't change the identifiers of any other keys.
+///
 /// See [`InheritableLocalKey` documentation][`InheritableLocalKey`] for more
 /// information.
 ///
@@ -346,27 +4228,137 @@ macro_rules! inheritable_task_local {
     // empty (base case for the recursion)
    () => {};
 
-   ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty; $($rest:tt)*) => {
-       $crate::__inheritable_task_local_inner!($(#[$attr])* $vis $name, $t);
+   // Attributes for this crate are collected as calls to make on the key, or accessors to generate. Everything else
+   // is passed through.
+   (@attrs [$($attr:tt)*] [$($flag:ident)*] [$($accessors:tt)*] #[no_inherit] $($rest:tt)*) => {
+       $crate::inheritable_task_local!(@attrs [$($attr)*] [$($flag)* no_inherit] [$($accessors)*] $($rest)*);
+   };
+
+   (@attrs [$($attr:tt)*] [$($flag:ident)*] [$($accessors:tt)*] #[stacked] $($rest:tt)*) => {
+       $crate::inheritable_task_local!(@attrs [$($attr)*] [$($flag)* stacked] [$($accessors)*] $($rest)*);
+   };
+
+   (@attrs [$($attr:tt)*] [$($flag:ident)*] [] #[accessors($get:ident, $try_get:ident)] $($rest:tt)*) => {
+       $crate::inheritable_task_local!(@attrs [$($attr)*] [$($flag)*] [$get $try_get] $($rest)*);
+   };
+
+   (@attrs [$($attr:tt)*] [$($flag:ident)*] [$($accessors:tt)*] #[$next:meta] $($rest:tt)*) => {
+       $crate::inheritable_task_local!(@attrs [$($attr)* #[$next]] [$($flag)*] [$($accessors)*] $($rest)*);
+   };
+
+   (@attrs [$(#[$attr:meta])*] [$($flag:ident)*] [$($accessors:tt)*] $vis:vis static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => {
+       $crate::__inheritable_task_local_inner!($(#[$attr])* $vis $name, $t, [$($flag)*], [$($accessors)*], $init);
+       $crate::inheritable_task_local!($($rest)*);
+   };
+
+   (@attrs [$(#[$attr:meta])*] [$($flag:ident)*] [$($accessors:tt)*] $vis:vis static $name:ident: $t:ty = $init:expr) => {
+       $crate::__inheritable_task_local_inner!($(#[$attr])* $vis $name, $t, [$($flag)*], [$($accessors)*], $init);
+   };
+
+   (@attrs [$(#[$attr:meta])*] [$($flag:ident)*] [$($accessors:tt)*] $vis:vis static $name:ident: $t:ty; $($rest:tt)*) => {
+       $crate::__inheritable_task_local_inner!($(#[$attr])* $vis $name, $t, [$($flag)*], [$($accessors)*]);
        $crate::inheritable_task_local!($($rest)*);
    };
 
-   ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty) => {
-       $crate::__inheritable_task_local_inner!($(#[$attr])* $vis $name, $t);
-   }
+   (@attrs [$(#[$attr:meta])*] [$($flag:ident)*] [$($accessors:tt)*] $vis:vis static $name:ident: $t:ty) => {
+       $crate::__inheritable_task_local_inner!($(#[$attr])* $vis $name, $t, [$($flag)*], [$($accessors)*]);
+   };
+
+   // Anything else is malformed. Without this it would be matched by the arm below and recurse forever.
+   (@attrs $($rest:tt)*) => {
+       ::std::compile_error!(
+           "expected a declaration like `static NAME: Type;`, optionally with attributes and an initializer"
+       );
+   };
+
+   ($($tokens:tt)+) => {
+       $crate::inheritable_task_local!(@attrs [] [] [] $($tokens)+);
+   };
 }
 
 #[doc(hidden)]
 #[macro_export]
 macro_rules! __inheritable_task_local_inner {
-   ($(#[$attr:meta])* $vis:vis $name:ident, $t:ty) => {
+   ($(#[$attr:meta])* $vis:vis $name:ident, $t:ty, [$($flag:ident)*], [$($get:ident $try_get:ident)?] $(, $init:expr)?) => {
        $(#[$attr])*
-       $vis static $name: $crate::InheritableLocalKey<$t> = $crate::InheritableLocalKey {
-            key: $crate::const_random::const_random!(u128),
-            _phantom: ::std::marker::PhantomData,
-       };
+       $vis static $name: $crate::InheritableLocalKey<$t> = $crate::InheritableLocalKey::__new(
+            ::std::concat!(::std::module_path!(), "::", ::std::stringify!($name)),
+            ::std::concat!(::std::file!(), ":", ::std::line!(), ":", ::std::column!()),
+            ::std::option_env!("CARGO_PKG_VERSION"),
+            $crate::KeyOptions::new()$(.$flag())*$(.init($init))?,
+       );
+
+       $(
+           $crate::__audit_track_caller! {
+               #[doc = ::std::concat!("Returns a clone of the current value of [`", ::std::stringify!($name), "`].")]
+               ///
+               /// # Panics
+               ///
+               /// This function will panic if the task local doesn't have a value set.
+               #[allow(dead_code)]
+               $vis fn $get() -> $t {
+                   $name.with(::std::clone::Clone::clone)
+               }
+           }
+
+           $crate::__audit_track_caller! {
+               #[doc = ::std::concat!("Returns a clone of the current value of [`", ::std::stringify!($name), "`], or the reason it isn't available.")]
+               #[allow(dead_code)]
+               $vis fn $try_get() -> ::std::result::Result<$t, $crate::InheritableAccessError> {
+                   $name.try_with(::std::clone::Clone::clone)
+               }
+           }
+       )?
    };
 }
 
+/// Adds `#[track_caller]` to the accessors generated by [`inheritable_task_local!`] when the `audit` feature is
+/// enabled. Checked here rather than in the macro, where `feature` would refer to the features of the calling crate.
+#[doc(hidden)]
+#[macro_export]
+#[cfg(feature = "audit")]
+macro_rules! __audit_track_caller {
+    ($item:item) => {
+        #[track_caller]
+        $item
+    };
+}
+
 #[doc(hidden)]
-pub use const_random;
+#[macro_export]
+#[cfg(not(feature = "audit"))]
+macro_rules! __audit_track_caller {
+    ($item:item) => {
+        $item
+    };
+}
+
+/// Computes the identifier of a key from its name and the place it was declared at using 128 bit FNV-1a.
+///
+/// Names alone aren't unique, keys declared inside of functions get the path of the enclosing module. The site is the
+/// file, line and column of the declaration, which tells those apart. Backslashes in it are hashed as forward slashes,
+/// so the identifier doesn't depend on the path separator of the platform. The version of the declaring crate is
+/// mixed in too, so two semver incompatible versions of the same crate don't end up sharing keys.
+const fn key_id(name: &str, site: &str, version: Option<&str>) -> u128 {
+    const OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+
+    const fn hash_bytes(mut hash: u128, bytes: &[u8]) -> u128 {
+        let mut i = 0;
+        while i < bytes.len() {
+            let byte = if bytes[i] == b'\\' { b'/' } else { bytes[i] };
+            hash ^= byte as u128;
+            hash = hash.wrapping_mul(PRIME);
+            i += 1;
+        }
+        hash
+    }
+
+    // 0xff never appears in UTF-8, so it can't be confused with part of the name or site.
+    let hash = hash_bytes(OFFSET_BASIS, name.as_bytes());
+    let hash = hash_bytes(hash_bytes(hash, &[0xff]), site.as_bytes());
+    match version {
+        Some(version) => hash_bytes(hash_bytes(hash, &[0xff]), version.as_bytes()),
+        None => hash,
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    fmt::{Debug, Formatter, Result as FmtResult},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+/// A value behind a [`Mutex`], which can be stored in an inheritable task local even if it isn't [`Sync`].
+///
+/// Inheritable task local values are shared between tasks, so they must be [`Send`] and [`Sync`]. Wrapping a value
+/// which is only [`Send`], such as a [`RefCell`](std::cell::RefCell), in `Shared` makes it storable. Every task which
+/// inherits the value locks the same [`Mutex`], so changes made by one task are seen by the others.
+///
+/// # Example
+///
+/// ```
+/// # async fn dox() {
+/// use std::cell::RefCell;
+/// use tokio_inherit_task_local::{inheritable_task_local, FutureInheritTaskLocal as _, Shared};
+///
+/// inheritable_task_local! {
+///     static EVENTS: Shared<RefCell<Vec<&'static str>>>;
+/// }
+///
+/// let events = Shared::new(RefCell::new(Vec::new()));
+/// EVENTS.scope(events.clone(), async {
+///     let child = async { EVENTS.with(|events| events.lock().borrow_mut().push("child")) };
+///     tokio::spawn(child.inherit_task_local()).await.unwrap();
+/// }).await;
+/// assert_eq!(*events.lock().borrow(), ["child"]);
+/// # }
+/// ```
+pub struct Shared<T> {
+    inner: Arc<Mutex<T>>,
+}
+
+impl<T> Shared<T> {
+    /// Wraps `value` so it can be stored in an inheritable task local.
+    pub fn new(value: T) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(value)),
+        }
+    }
+
+    /// Locks the value, blocking the current thread until it's available.
+    ///
+    /// If a thread panicked while holding the lock the value is returned anyway, since it's up to the value whether
+    /// it was left in a consistent state.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns `true` if both refer to the same value.
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        Arc::ptr_eq(&this.inner, &other.inner)
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    /// Returns another reference to the same value.
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: Default> Default for Shared<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> Debug for Shared<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        // Printing the value would need to take the lock.
+        f.debug_struct("Shared").finish()
+    }
+}
@@ -0,0 +1,46 @@
+use std::future::Future;
+
+use tokio::task::JoinHandle;
+use tokio_util::task::TaskTracker;
+
+use crate::FutureInheritTaskLocal as _;
+
+/// Extends [`TaskTracker`] with a method that spawns tasks which inherit the current inheritable task local values.
+/// Requires the `tokio-util` feature.
+pub trait TaskTrackerInheritExt {
+    /// Spawns `f` onto the current runtime and tracks it with this [`TaskTracker`], like [`TaskTracker::spawn`].
+    /// The new task inherits the inheritable task local values which are currently available.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// use tokio_inherit_task_local::{inheritable_task_local, TaskTrackerInheritExt as _};
+    /// use tokio_util::task::TaskTracker;
+    ///
+    /// inheritable_task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// let tracker = TaskTracker::new();
+    /// let handle = NUMBER.sync_scope(1, || tracker.spawn_inheriting(async { NUMBER.get() }));
+    /// tracker.close();
+    /// tracker.wait().await;
+    /// assert_eq!(handle.await.unwrap(), 1);
+    /// # }
+    /// ```
+    fn spawn_inheriting<F>(&self, f: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static;
+}
+
+impl TaskTrackerInheritExt for TaskTracker {
+    fn spawn_inheriting<F>(&self, f: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn(f.inherit_task_local())
+    }
+}
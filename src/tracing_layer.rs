@@ -0,0 +1,75 @@
+use std::sync::Arc;
+
+use tracing_core::{
+    span::{Attributes, Id},
+    Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+use crate::{current_table, pop_restored, push_restored, without_no_inherit, TaskLocalMap};
+
+/// A [`Layer`] which captures the inheritable task local values that are available when a span is created, and
+/// restores them whenever that span is entered. This makes the values available to code running inside of the span,
+/// even if it's entered on another thread or outside of any scope.
+///
+/// Values set by the current task take precedence over the ones restored from a span. Values declared with
+/// `#[no_inherit]` aren't captured.
+///
+/// Requires the `tracing-subscriber` feature.
+///
+/// # Example
+///
+/// ```
+/// use tokio_inherit_task_local::{inheritable_task_local, SpanContextLayer};
+/// use tracing_subscriber::layer::SubscriberExt as _;
+///
+/// inheritable_task_local! {
+///     static REQUEST_ID: u64;
+/// }
+///
+/// let subscriber = tracing_subscriber::registry().with(SpanContextLayer::default());
+/// tracing::subscriber::with_default(subscriber, || {
+///     let span = REQUEST_ID.sync_scope(7, || tracing::info_span!("request"));
+///     assert_eq!(span.in_scope(|| REQUEST_ID.get()), 7);
+/// });
+/// ```
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SpanContextLayer {
+    _private: (),
+}
+
+/// Stored in the extensions of each span created while inheritable task local values were available.
+struct SpanContext(Arc<TaskLocalMap>);
+
+impl<S> Layer<S> for SpanContextLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let (Some(span), Some(task_locals)) = (ctx.span(id), current_table()) else {
+            return;
+        };
+        let task_locals = without_no_inherit(task_locals).share();
+        span.extensions_mut().insert(SpanContext(task_locals));
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let extensions = span.extensions();
+        if let Some(SpanContext(task_locals)) = extensions.get::<SpanContext>() {
+            push_restored(task_locals.clone());
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let extensions = span.extensions();
+        if let Some(SpanContext(task_locals)) = extensions.get::<SpanContext>() {
+            pop_restored(Arc::as_ptr(task_locals));
+        }
+    }
+}
@@ -1,5 +1,15 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
 use tokio_inherit_task_local::{
-    inheritable_task_local, FutureInheritTaskLocal, InheritableAccessError,
+    inheritable_task_local, thread_spawn_inheriting, try_with2, try_with3, try_with4, with2, with3,
+    FutureInheritTaskLocal, InheritableAccessError,
 };
 
 inheritable_task_local! {
@@ -7,6 +17,14 @@ inheritable_task_local! {
     pub static ANOTHER_TEST_VALUE: String;
 }
 
+struct DropCounter(Arc<AtomicUsize>);
+
+impl Drop for DropCounter {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
 #[tokio::test]
 async fn basic() {
     let out = TEST_VALUE.scope(5, async { TEST_VALUE.with(|&v| v) }).await;
@@ -66,6 +84,26 @@ async fn inherit_repeatedly() {
     assert_eq!(out, 5);
 }
 
+#[tokio::test]
+async fn inherit_deeply_shares_table() {
+    fn nest(depth: usize) -> Pin<Box<dyn Future<Output = usize> + Send>> {
+        Box::pin(async move {
+            if depth == 0 {
+                Arc::strong_count(&TEST_VALUE.get_arc())
+            } else {
+                tokio::spawn(nest(depth - 1).inherit_task_local())
+                    .await
+                    .unwrap()
+            }
+        })
+    }
+
+    // Every level is still running when the innermost one checks. If any level had copied the table, the value
+    // would have a reference for each copy.
+    let count = TEST_VALUE.scope(5, nest(1000)).await;
+    assert_eq!(count, 2);
+}
+
 #[tokio::test]
 async fn basic_sync() {
     let out = TEST_VALUE.sync_scope(5, || TEST_VALUE.with(|&v| v));
@@ -135,3 +173,2217 @@ async fn both_values_together_now() {
     assert_eq!(uint, 5);
     assert_eq!(str, "foo");
 }
+
+#[tokio::test]
+async fn thread_spawn_inherit() {
+    let out = TEST_VALUE
+        .scope(5, async {
+            thread_spawn_inheriting(|| TEST_VALUE.with(|&v| v))
+                .join()
+                .unwrap()
+        })
+        .await;
+    assert_eq!(out, 5);
+}
+
+#[cfg(feature = "rt-multi-thread")]
+#[tokio::test(flavor = "multi_thread")]
+async fn block_in_place_inherit() {
+    let out = TEST_VALUE
+        .scope(5, async {
+            tokio_inherit_task_local::block_in_place_inheriting(|| TEST_VALUE.with(|&v| v))
+        })
+        .await;
+    assert_eq!(out, 5);
+}
+
+#[tokio::test]
+async fn update() {
+    let (out, inherited) = TEST_VALUE
+        .scope(5, async {
+            let child = tokio::spawn(async { TEST_VALUE.with(|&v| v) }.inherit_task_local());
+            TEST_VALUE.update(|v| v + 1).unwrap();
+            (TEST_VALUE.with(|&v| v), child.await.unwrap())
+        })
+        .await;
+    assert_eq!(out, 6);
+    assert_eq!(inherited, 5);
+}
+
+#[tokio::test]
+async fn fail_update() {
+    let out = async { TEST_VALUE.update(|v| v + 1) }.await.unwrap_err();
+    assert_eq!(out, InheritableAccessError::NotInTokio);
+    let out = ANOTHER_TEST_VALUE
+        .scope(String::from("foo"), async { TEST_VALUE.update(|v| v + 1) })
+        .await
+        .unwrap_err();
+    assert_eq!(out, InheritableAccessError::NotInTable);
+}
+
+#[test]
+fn key_name() {
+    assert_eq!(TEST_VALUE.key_name(), "full::TEST_VALUE");
+    assert_eq!(ANOTHER_TEST_VALUE.key_name(), "full::ANOTHER_TEST_VALUE");
+}
+
+#[tokio::test]
+async fn is_set() {
+    assert!(!TEST_VALUE.is_set());
+    let (test_value, another_test_value) = TEST_VALUE
+        .scope(5, async {
+            (TEST_VALUE.is_set(), ANOTHER_TEST_VALUE.is_set())
+        })
+        .await;
+    assert!(test_value);
+    assert!(!another_test_value);
+}
+
+#[cfg(feature = "macros")]
+#[tokio_inherit_task_local::inherit_task_local]
+async fn read_test_value() -> u32 {
+    TEST_VALUE.with(|&v| v)
+}
+
+#[cfg(feature = "macros")]
+#[tokio::test]
+async fn inherit_task_local_attribute() {
+    let out = TEST_VALUE
+        .scope(5, async { tokio::spawn(read_test_value()).await })
+        .await
+        .unwrap();
+    assert_eq!(out, 5);
+}
+
+#[cfg(feature = "macros")]
+#[derive(tokio_inherit_task_local::FromInheritableContext)]
+struct DerivedContext {
+    #[inheritable(TEST_VALUE)]
+    number: u32,
+    #[inheritable(ANOTHER_TEST_VALUE)]
+    text: String,
+}
+
+#[cfg(feature = "macros")]
+#[test]
+fn derive_from_inheritable_context() {
+    let context = TEST_VALUE.sync_scope(7, || {
+        ANOTHER_TEST_VALUE.sync_scope(String::from("seven"), || {
+            tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap()
+                .block_on(async {
+                    tokio::spawn(async { DerivedContext::from_current() }.inherit_task_local())
+                        .await
+                })
+                .unwrap()
+        })
+    });
+    let context = context.unwrap();
+    assert_eq!(context.number, 7);
+    assert_eq!(context.text, "seven");
+    assert!(TEST_VALUE
+        .sync_scope(7, DerivedContext::from_current)
+        .is_err());
+}
+
+#[test]
+fn fold_keys() {
+    use std::any::Any;
+    use tokio_inherit_task_local::fold_keys;
+
+    let summarize = |mut summary: String, value: &dyn Any| {
+        if let Some(v) = value.downcast_ref::<u32>() {
+            summary += &format!("number={v};");
+        }
+        if let Some(v) = value.downcast_ref::<String>() {
+            summary += &format!("text={v};");
+        }
+        summary
+    };
+    let summary = TEST_VALUE.sync_scope(3, || {
+        ANOTHER_TEST_VALUE.sync_scope(String::from("foo"), || {
+            fold_keys(
+                &[&TEST_VALUE, &ANOTHER_TEST_VALUE],
+                String::new(),
+                summarize,
+            )
+        })
+    });
+    assert_eq!(summary, "number=3;text=foo;");
+    assert_eq!(
+        fold_keys(
+            &[&TEST_VALUE, &ANOTHER_TEST_VALUE],
+            String::new(),
+            summarize
+        ),
+        ""
+    );
+}
+
+#[cfg(feature = "task-id")]
+#[tokio::test]
+async fn task_id_lineage() {
+    let spawner = tokio::spawn(TEST_VALUE.scope(1, async {
+        let child = async {
+            let table = tokio_inherit_task_local::current_table().unwrap();
+            (
+                tokio::task::id(),
+                table.origin_task_id(),
+                table.parent_task_id(),
+            )
+        };
+        let child = tokio::spawn(child.inherit_task_local()).await.unwrap();
+        (tokio::task::id(), child)
+    }));
+    let (spawner, (child, origin, parent)) = spawner.await.unwrap();
+    assert_eq!(origin, Some(child));
+    assert_eq!(parent, Some(spawner));
+}
+
+#[tokio::test]
+async fn scope_boxed() {
+    struct Pending {
+        futures: Vec<Pin<Box<dyn Future<Output = String> + Send>>>,
+    }
+
+    let pending = Pending {
+        futures: vec![
+            ANOTHER_TEST_VALUE.scope_boxed(String::from("a"), async { ANOTHER_TEST_VALUE.get() }),
+            ANOTHER_TEST_VALUE.scope_boxed(String::from("b"), async {
+                tokio::spawn(async { ANOTHER_TEST_VALUE.get() }.inherit_task_local())
+                    .await
+                    .unwrap()
+            }),
+        ],
+    };
+    let mut outputs = Vec::new();
+    for future in pending.futures {
+        outputs.push(future.await);
+    }
+    assert_eq!(outputs, ["a", "b"]);
+}
+
+#[tokio::test]
+async fn for_each_inheriting() {
+    inheritable_task_local! {
+        static SHARD: usize;
+    }
+
+    let handles = ANOTHER_TEST_VALUE
+        .scope(String::from("shared"), async {
+            tokio_inherit_task_local::for_each_inheriting(
+                [10, 20, 30],
+                &SHARD,
+                |&shard| async move { (shard, SHARD.get(), ANOTHER_TEST_VALUE.get()) },
+            )
+        })
+        .await;
+    let mut outputs = Vec::new();
+    for handle in handles {
+        outputs.push(handle.await.unwrap());
+    }
+    assert_eq!(
+        outputs,
+        [
+            (10, 10, String::from("shared")),
+            (20, 20, String::from("shared")),
+            (30, 30, String::from("shared")),
+        ]
+    );
+}
+
+#[cfg(feature = "time")]
+#[tokio::test]
+async fn timeout_inheriting() {
+    use std::time::Duration;
+    use tokio_inherit_task_local::timeout_inheriting;
+
+    let timeout = TEST_VALUE.sync_scope(4, || {
+        timeout_inheriting(Duration::from_secs(10), async {
+            tokio::task::yield_now().await;
+            TEST_VALUE.get()
+        })
+    });
+    // Awaited from another task, which doesn't have the value.
+    let out = tokio::spawn(timeout).await.unwrap();
+    assert_eq!(out, Ok(4));
+
+    let timed_out = TEST_VALUE
+        .scope(
+            4,
+            timeout_inheriting(Duration::from_millis(1), std::future::pending::<()>()),
+        )
+        .await;
+    assert!(timed_out.is_err());
+
+    inheritable_task_local! {
+        #[no_inherit]
+        static TOKEN: u32;
+    }
+    let out = TOKEN
+        .scope(5, async {
+            timeout_inheriting(Duration::from_secs(10), async { TOKEN.is_set() }).await
+        })
+        .await;
+    assert_eq!(out, Ok(true));
+}
+
+#[tokio::test]
+async fn generated_accessors() {
+    inheritable_task_local! {
+        #[accessors(foo, try_foo)]
+        static FOO: u32;
+    }
+
+    let out = FOO
+        .scope(3, async {
+            tokio::spawn(async { foo() }.inherit_task_local()).await
+        })
+        .await
+        .unwrap();
+    assert_eq!(out, 3);
+    assert_eq!(try_foo(), Err(InheritableAccessError::NotInTokio));
+}
+
+#[tokio::test]
+async fn scope_lazy() {
+    static MADE: AtomicUsize = AtomicUsize::new(0);
+
+    let make = || {
+        MADE.fetch_add(1, Ordering::SeqCst);
+        8
+    };
+    drop(TEST_VALUE.scope_lazy(make, async { TEST_VALUE.get() }));
+    assert_eq!(MADE.load(Ordering::SeqCst), 0);
+
+    let out = TEST_VALUE
+        .scope_lazy(make, async {
+            tokio::task::yield_now().await;
+            TEST_VALUE.get()
+        })
+        .await;
+    assert_eq!(out, 8);
+    assert_eq!(MADE.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn read_in_drop_on_update() {
+    static READ_IN_DROP: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Clone)]
+    struct ReadOnDrop;
+
+    impl Drop for ReadOnDrop {
+        fn drop(&mut self) {
+            let v = TEST_VALUE.try_with(|&v| v as usize).unwrap();
+            READ_IN_DROP.fetch_add(v, Ordering::SeqCst);
+        }
+    }
+
+    inheritable_task_local! {
+        static READS_ON_DROP: ReadOnDrop;
+    }
+
+    TEST_VALUE.sync_scope(3, || {
+        READS_ON_DROP.sync_scope(ReadOnDrop, || {
+            // The copy handed to the closure and the replaced value are both dropped.
+            READS_ON_DROP.update(|_| ReadOnDrop).unwrap();
+            assert_eq!(READ_IN_DROP.load(Ordering::SeqCst), 6);
+            drop(READS_ON_DROP.take().unwrap());
+            assert_eq!(READ_IN_DROP.load(Ordering::SeqCst), 9);
+        });
+    });
+}
+
+#[tokio::test]
+async fn read_in_drop_on_cancel() {
+    static READ_IN_DROP: AtomicUsize = AtomicUsize::new(0);
+
+    struct ReadOnDrop;
+
+    impl Drop for ReadOnDrop {
+        fn drop(&mut self) {
+            let v = TEST_VALUE.try_with(|&v| v as usize).unwrap();
+            READ_IN_DROP.store(v, Ordering::SeqCst);
+        }
+    }
+
+    let (started, is_started) = tokio::sync::oneshot::channel();
+    let child = async move {
+        let _guard = ReadOnDrop;
+        started.send(()).unwrap();
+        std::future::pending::<()>().await;
+    };
+    let handle = TEST_VALUE.sync_scope(9, || tokio::spawn(child.inherit_task_local()));
+    is_started.await.unwrap();
+    handle.abort();
+    assert!(handle.await.unwrap_err().is_cancelled());
+    assert_eq!(READ_IN_DROP.load(Ordering::SeqCst), 9);
+}
+
+#[test]
+fn context_macro() {
+    let restore = TEST_VALUE.sync_scope(12, || tokio_inherit_task_local::context!());
+    let restore = &restore;
+    std::thread::scope(|s| {
+        let workers = (0..3)
+            .map(|_| {
+                s.spawn(move || {
+                    assert!(!TEST_VALUE.is_set());
+                    let guard = restore();
+                    let v = TEST_VALUE.get();
+                    drop(guard);
+                    (v, TEST_VALUE.is_set())
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            assert_eq!(worker.join().unwrap(), (12, false));
+        }
+    });
+}
+
+#[test]
+fn restored_only_outside_scopes() {
+    use tokio_inherit_task_local::{with_raw, CapturedContext, TaskLocalInheritableTable};
+
+    let context = TEST_VALUE.sync_scope(5, CapturedContext::capture);
+    let _guard = context.restore();
+    let raw = || with_raw(TEST_VALUE.id(), |v| v.is_some());
+    assert!(TEST_VALUE.is_set());
+    assert_eq!(TEST_VALUE.get(), 5);
+    assert!(raw());
+
+    let empty = TaskLocalInheritableTable::default()
+        .enter_sync_scope(|| (TEST_VALUE.is_set(), TEST_VALUE.try_with(|&v| v), raw()));
+    assert_eq!(
+        empty,
+        (false, Err(InheritableAccessError::NotInTable), false)
+    );
+    let taken = TEST_VALUE.sync_scope(6, || {
+        TEST_VALUE.take().unwrap();
+        (TEST_VALUE.try_with(|&v| v), raw())
+    });
+    assert_eq!(taken, (Err(InheritableAccessError::NotInTable), false));
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(
+    expected = "ContextGuard dropped while values restored after it were still available"
+)]
+fn restore_dropped_out_of_order() {
+    let first = TEST_VALUE.sync_scope(1, tokio_inherit_task_local::CapturedContext::capture);
+    let second = TEST_VALUE.sync_scope(2, tokio_inherit_task_local::CapturedContext::capture);
+    let first_guard = first.restore();
+    let _second_guard = second.restore();
+    drop(first_guard);
+}
+
+#[tokio::test]
+async fn shared_not_sync() {
+    use std::cell::RefCell;
+    use tokio_inherit_task_local::Shared;
+
+    inheritable_task_local! {
+        static LOG: Shared<RefCell<Vec<u32>>>;
+    }
+
+    let log = Shared::new(RefCell::new(vec![1]));
+    LOG.scope(log.clone(), async {
+        let child = async {
+            LOG.with(|log| log.lock().borrow_mut().push(2));
+            tokio::spawn(
+                async { LOG.with(|log| log.lock().borrow_mut().push(3)) }.inherit_task_local(),
+            )
+            .await
+            .unwrap();
+        };
+        tokio::spawn(child.inherit_task_local()).await.unwrap();
+        assert!(LOG.with(|current| Shared::ptr_eq(current, &log)));
+    })
+    .await;
+    assert_eq!(*log.lock().borrow(), [1, 2, 3]);
+}
+
+#[tokio::test]
+async fn scope_owned_into_table() {
+    let mut first = TEST_VALUE.scope_owned(1, async {
+        TEST_VALUE.update(|v| v + 1).unwrap();
+        TEST_VALUE.get()
+    });
+    assert_eq!((&mut first).await, 2);
+    let table = first.into_table();
+    // The table keeps the update made by the first future.
+    let second = table.enter_scope(async {
+        tokio::spawn(async { TEST_VALUE.get() }.inherit_task_local())
+            .await
+            .unwrap()
+    });
+    assert_eq!(second.await, 2);
+}
+
+#[tokio::test]
+async fn dump_current() {
+    use tokio_inherit_task_local::dump_current;
+
+    assert!(dump_current().is_empty());
+    let values = TEST_VALUE
+        .scope(6, async {
+            ANOTHER_TEST_VALUE
+                .scope(String::from("six"), async {
+                    tokio::spawn(async { dump_current() }.inherit_task_local())
+                        .await
+                        .unwrap()
+                })
+                .await
+        })
+        .await;
+    assert_eq!(values.len(), 2);
+    let find = |id| &values.iter().find(|(key, _)| *key == id).unwrap().1;
+    assert_eq!(find(TEST_VALUE.id()).downcast_ref::<u32>(), Some(&6));
+    assert_eq!(
+        find(ANOTHER_TEST_VALUE.id())
+            .downcast_ref::<String>()
+            .map(String::as_str),
+        Some("six")
+    );
+}
+
+#[cfg(feature = "tokio-util")]
+#[tokio::test]
+async fn task_tracker_spawn_inheriting() {
+    use tokio_inherit_task_local::TaskTrackerInheritExt as _;
+    use tokio_util::task::TaskTracker;
+
+    let tracker = TaskTracker::new();
+    let handles = TEST_VALUE.sync_scope(11, || {
+        (0..3)
+            .map(|i| tracker.spawn_inheriting(async move { TEST_VALUE.get() + i }))
+            .collect::<Vec<_>>()
+    });
+    tracker.close();
+    tracker.wait().await;
+    let mut outputs = Vec::new();
+    for handle in handles {
+        outputs.push(handle.await.unwrap());
+    }
+    assert_eq!(outputs, [11, 12, 13]);
+}
+
+#[tokio::test]
+async fn in_scope() {
+    use tokio_inherit_task_local::in_scope;
+
+    assert!(!in_scope());
+    assert!(TEST_VALUE.scope(1, async { in_scope() }).await);
+    assert!(tokio_inherit_task_local::scope_empty(async { in_scope() }).await);
+    let child = TEST_VALUE
+        .scope(1, async {
+            tokio::spawn(async { in_scope() }.inherit_task_local())
+                .await
+                .unwrap()
+        })
+        .await;
+    assert!(child);
+    assert!(!tokio::spawn(async { in_scope() }).await.unwrap());
+}
+
+#[cfg(feature = "macros")]
+#[tokio::test]
+async fn join_and_select_inheriting() {
+    use tokio_inherit_task_local::{join_inheriting, select_inheriting};
+
+    let spawn_child = || async {
+        tokio::spawn(async { TEST_VALUE.get() }.inherit_task_local())
+            .await
+            .unwrap()
+    };
+    let joined = TEST_VALUE
+        .scope(3, async { join_inheriting!(spawn_child(), spawn_child()) })
+        .await;
+    assert_eq!(joined, (3, 3));
+
+    let selected = TEST_VALUE
+        .scope(4, async {
+            select_inheriting! {
+                a = spawn_child() => a,
+                b = spawn_child() => b,
+            }
+        })
+        .await;
+    assert_eq!(selected, 4);
+
+    inheritable_task_local! {
+        #[no_inherit]
+        static TOKEN: u32;
+    }
+    let in_branches = TOKEN
+        .scope(5, async {
+            let (joined,) = join_inheriting!(async { TOKEN.is_set() });
+            let selected = select_inheriting! {
+                set = async { TOKEN.is_set() } => set,
+            };
+            (joined, selected)
+        })
+        .await;
+    assert_eq!(in_branches, (true, true));
+}
+
+#[tokio::test]
+async fn spawn_local_not_send() {
+    use std::rc::Rc;
+
+    let local = tokio::task::LocalSet::new();
+    let out = local
+        .run_until(TEST_VALUE.scope(13, async {
+            // Holding an Rc across an await makes the child !Send.
+            let child = async {
+                let not_send = Rc::new(TEST_VALUE.get());
+                tokio::task::yield_now().await;
+                *not_send + TEST_VALUE.get()
+            };
+            tokio::task::spawn_local(child.inherit_task_local())
+                .await
+                .unwrap()
+        }))
+        .await;
+    assert_eq!(out, 26);
+}
+
+#[test]
+fn local_set_entry_points() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let local = tokio::task::LocalSet::new();
+
+    // Wrapped with `sync_scope` outside of the `LocalSet`, then spawned onto it.
+    let from_sync = TEST_VALUE.sync_scope(1, || {
+        local.spawn_local(async { TEST_VALUE.get() }.inherit_task_local())
+    });
+    // A scope inside of a task on the `LocalSet`, passed down with `spawn_local`.
+    let from_scope = local.spawn_local(TEST_VALUE.scope(2, async {
+        let child = tokio::task::spawn_local(async { TEST_VALUE.get() }.inherit_task_local());
+        let not_inherited = tokio::task::spawn_local(async { TEST_VALUE.is_set() });
+        (child.await.unwrap(), not_inherited.await.unwrap())
+    }));
+    let out = runtime
+        .block_on(local.run_until(async { (from_sync.await.unwrap(), from_scope.await.unwrap()) }));
+    assert_eq!(out, (1, (2, false)));
+}
+
+#[test]
+fn sync_scope_runtime_spawn() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .build()
+        .unwrap();
+    // Spawned from a thread outside of the runtime, while the `sync_scope` is still on its stack.
+    let handle = TEST_VALUE.sync_scope(5, || {
+        runtime.spawn(
+            async {
+                tokio::task::yield_now().await;
+                TEST_VALUE.get()
+            }
+            .inherit_task_local(),
+        )
+    });
+    assert_eq!(runtime.block_on(handle).unwrap(), 5);
+}
+
+#[test]
+fn block_on_scope() {
+    let out = TEST_VALUE.block_on_scope(14, async {
+        tokio::spawn(async { TEST_VALUE.get() }.inherit_task_local())
+            .await
+            .unwrap()
+    });
+    assert_eq!(out, 14);
+}
+
+#[tokio::test]
+#[should_panic(expected = "block_on_scope can't be called from inside a tokio runtime")]
+async fn block_on_scope_in_runtime() {
+    TEST_VALUE.block_on_scope(14, async {});
+}
+
+#[tokio::test]
+async fn with_raw() {
+    use tokio_inherit_task_local::with_raw;
+
+    let (set, unset) = TEST_VALUE
+        .scope(15, async {
+            (
+                with_raw(TEST_VALUE.id(), |v| {
+                    v.and_then(|v| v.downcast_ref::<u32>()).copied()
+                }),
+                with_raw(ANOTHER_TEST_VALUE.id(), |v| v.is_none()),
+            )
+        })
+        .await;
+    assert_eq!(set, Some(15));
+    assert!(unset);
+}
+
+#[cfg(feature = "sync")]
+#[tokio::test]
+async fn respond_with_context() {
+    use tokio::sync::{mpsc, oneshot};
+    use tokio_inherit_task_local::{respond_with_context, CapturedContext};
+
+    let (requests, mut incoming) =
+        mpsc::unbounded_channel::<(CapturedContext, oneshot::Sender<u32>)>();
+    // Spawned outside of any scope, so the worker can only see the values sent with each request.
+    let worker = tokio::spawn(async move {
+        while let Some((context, responder)) = incoming.recv().await {
+            respond_with_context(&context, responder, || TEST_VALUE.get() * 2).unwrap();
+        }
+    });
+    for value in [1, 2] {
+        let response = TEST_VALUE
+            .scope(value, async {
+                let (responder, response) = oneshot::channel();
+                requests
+                    .send((CapturedContext::capture(), responder))
+                    .unwrap();
+                response.await.unwrap()
+            })
+            .await;
+        assert_eq!(response, value * 2);
+    }
+    drop(requests);
+    worker.await.unwrap();
+}
+
+#[tokio::test]
+async fn builder_into_table() {
+    let table = tokio_inherit_task_local::builder()
+        .set(&TEST_VALUE, 18)
+        .set(&ANOTHER_TEST_VALUE, String::from("eighteen"))
+        .into_table();
+    assert_eq!(table.set_slots(), 2);
+    let out = table
+        .enter_scope(async {
+            tokio::spawn(
+                async { (TEST_VALUE.get(), ANOTHER_TEST_VALUE.get()) }.inherit_task_local(),
+            )
+            .await
+            .unwrap()
+        })
+        .await;
+    assert_eq!(out, (18, String::from("eighteen")));
+}
+
+#[tokio::test]
+async fn map() {
+    let out = ANOTHER_TEST_VALUE
+        .scope(String::from("foo"), async {
+            ANOTHER_TEST_VALUE.map(|v| v.len())
+        })
+        .await;
+    assert_eq!(out, Ok(3));
+    assert_eq!(
+        ANOTHER_TEST_VALUE.map(|v| v.len()),
+        Err(InheritableAccessError::NotInTokio)
+    );
+}
+
+#[test]
+fn same_name_in_one_module() {
+    fn number() -> &'static tokio_inherit_task_local::InheritableLocalKey<u32> {
+        inheritable_task_local! {
+            static SAME: u32;
+        }
+        &SAME
+    }
+
+    fn text() -> &'static tokio_inherit_task_local::InheritableLocalKey<String> {
+        inheritable_task_local! {
+            static SAME: String;
+        }
+        &SAME
+    }
+
+    assert_eq!(number().key_name(), text().key_name());
+    assert_ne!(number().id(), text().id());
+    let (number_value, text_value) = tokio_inherit_task_local::builder()
+        .set(number(), 7)
+        .set(text(), "seven".to_owned())
+        .sync_scope(|| (number().get(), text().get()));
+    assert_eq!(number_value, 7);
+    assert_eq!(text_value, "seven");
+}
+
+#[test]
+fn type_mismatch() {
+    use tokio_inherit_task_local::{InheritableLocalKey, KeyOptions};
+
+    // Two keys with the same name, site and version collide, simulating a key being reused for a different type.
+    static NUMBER: InheritableLocalKey<u32> =
+        InheritableLocalKey::__new("collision", "site", None, KeyOptions::new());
+    static TEXT: InheritableLocalKey<String> =
+        InheritableLocalKey::__new("collision", "site", None, KeyOptions::new());
+
+    assert_eq!(NUMBER.id(), TEXT.id());
+    NUMBER.sync_scope(1, || {
+        assert_eq!(
+            TEXT.try_with(|v| v.len()),
+            Err(InheritableAccessError::TypeMismatch)
+        );
+        assert_eq!(
+            tokio_inherit_task_local::try_with2(&NUMBER, &TEXT, |_, _| ()),
+            Err(InheritableAccessError::TypeMismatch)
+        );
+        assert_eq!(NUMBER.get(), 1);
+    });
+}
+
+#[cfg(feature = "test-default")]
+#[test]
+fn test_default() {
+    inheritable_task_local! {
+        static DEFAULTED: u32;
+    }
+
+    assert_eq!(
+        DEFAULTED.try_with(|&v| v),
+        Err(InheritableAccessError::NotInTokio)
+    );
+    DEFAULTED.set_test_default(5);
+    assert_eq!(DEFAULTED.with(|&v| v), 5);
+    assert_eq!(DEFAULTED.sync_scope(6, || DEFAULTED.with(|&v| v)), 6);
+}
+
+#[cfg(feature = "futures")]
+#[tokio::test]
+async fn inherit_futures_unordered() {
+    use futures_util::{stream::FuturesUnordered, StreamExt as _};
+    use tokio_inherit_task_local::FuturesUnorderedInheritTaskLocal as _;
+
+    let futures = TEST_VALUE.sync_scope(5, || {
+        (0..3)
+            .map(|i| async move { TEST_VALUE.with(|&v| v + i) })
+            .collect::<FuturesUnordered<_>>()
+            .inherit_all()
+    });
+    let mut out = tokio::spawn(futures.collect::<Vec<_>>()).await.unwrap();
+    out.sort();
+    assert_eq!(out, [5, 6, 7]);
+}
+
+#[tokio::test]
+async fn take() {
+    let (taken, after, inherited) = ANOTHER_TEST_VALUE
+        .scope(String::from("foo"), async {
+            let child =
+                tokio::spawn(async { ANOTHER_TEST_VALUE.with(|v| v.clone()) }.inherit_task_local());
+            let taken = ANOTHER_TEST_VALUE.take().unwrap().unwrap();
+            let after = ANOTHER_TEST_VALUE.try_with(|v| v.clone());
+            (taken, after, child.await.unwrap())
+        })
+        .await;
+    assert_eq!(*taken, "foo");
+    assert_eq!(after, Err(InheritableAccessError::NotInTable));
+    assert_eq!(inherited, "foo");
+    assert_eq!(
+        ANOTHER_TEST_VALUE.take(),
+        Err(InheritableAccessError::NotInTokio)
+    );
+}
+
+#[test]
+fn pooled_tables_do_not_leak_values() {
+    inheritable_task_local! {
+        static POOLED: DropCounter;
+    }
+
+    let drops = Arc::new(AtomicUsize::new(0));
+    for _ in 0..100 {
+        POOLED.sync_scope(DropCounter(drops.clone()), || {});
+    }
+    assert_eq!(drops.load(Ordering::SeqCst), 100);
+    // Every table created from here on reuses an allocation from above.
+    for _ in 0..100 {
+        assert!(TEST_VALUE.sync_scope(5, || !POOLED.is_set()));
+    }
+}
+
+#[tokio::test]
+async fn with_multiple() {
+    inheritable_task_local! {
+        static THIRD: u8;
+        static FOURTH: bool;
+    }
+
+    let out = TEST_VALUE
+        .scope(5, async {
+            ANOTHER_TEST_VALUE
+                .scope(String::from("foo"), async {
+                    with2(&TEST_VALUE, &ANOTHER_TEST_VALUE, |&a, b| (a, b.clone()))
+                })
+                .await
+        })
+        .await;
+    assert_eq!(out, (5, String::from("foo")));
+
+    let out = THIRD
+        .scope(3, async {
+            FOURTH
+                .scope(true, async {
+                    TEST_VALUE
+                        .scope(5, async {
+                            (
+                                with3(&TEST_VALUE, &THIRD, &FOURTH, |&a, &c, &d| (a, c, d)),
+                                try_with4(
+                                    &TEST_VALUE,
+                                    &ANOTHER_TEST_VALUE,
+                                    &THIRD,
+                                    &FOURTH,
+                                    |_, _, _, _| (),
+                                ),
+                            )
+                        })
+                        .await
+                })
+                .await
+        })
+        .await;
+    assert_eq!(out.0, (5, 3, true));
+    assert_eq!(out.1, Err(InheritableAccessError::NotInTable));
+
+    assert_eq!(
+        try_with2(&TEST_VALUE, &ANOTHER_TEST_VALUE, |_, _| ()),
+        Err(InheritableAccessError::NotInTokio)
+    );
+    let out = TEST_VALUE
+        .scope(5, async {
+            try_with3(&TEST_VALUE, &THIRD, &FOURTH, |_, _, _| ())
+        })
+        .await;
+    assert_eq!(out, Err(InheritableAccessError::NotInTable));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_scopes_stress() {
+    inheritable_task_local! {
+        static STRESS_A: usize;
+        static STRESS_B: usize;
+        static STRESS_C: usize;
+    }
+
+    let handles = (0..256)
+        .map(|i| {
+            tokio::spawn(async move {
+                STRESS_A
+                    .scope(i, async move {
+                        STRESS_B
+                            .scope(i * 2, async move {
+                                tokio::task::yield_now().await;
+                                tokio::spawn(
+                                    STRESS_C
+                                        .scope(i * 3, async {
+                                            tokio::task::yield_now().await;
+                                            with3(&STRESS_A, &STRESS_B, &STRESS_C, |&a, &b, &c| {
+                                                (a, b, c)
+                                            })
+                                        })
+                                        .inherit_task_local(),
+                                )
+                                .await
+                                .unwrap()
+                            })
+                            .await
+                    })
+                    .await
+            })
+        })
+        .collect::<Vec<_>>();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.await.unwrap(), (i, i * 2, i * 3));
+    }
+}
+
+#[tokio::test]
+async fn scope_override() {
+    let out = TEST_VALUE
+        .scope_override(5, async {
+            TEST_VALUE
+                .scope(6, async {
+                    assert_eq!(
+                        TEST_VALUE.update(|v| v + 1),
+                        Err(InheritableAccessError::Locked)
+                    );
+                    assert_eq!(TEST_VALUE.take(), Err(InheritableAccessError::Locked));
+                    assert_eq!(
+                        TEST_VALUE.replace_arc(Arc::new(9)),
+                        Err(InheritableAccessError::Locked)
+                    );
+                    let inner = TEST_VALUE.sync_scope(7, || TEST_VALUE.get());
+                    let inherited = tokio::spawn(
+                        TEST_VALUE
+                            .scope(8, async { TEST_VALUE.get() })
+                            .inherit_task_local(),
+                    )
+                    .await
+                    .unwrap();
+                    (TEST_VALUE.get(), inner, inherited)
+                })
+                .await
+        })
+        .await;
+    assert_eq!(out, (5, 5, 5));
+}
+
+#[test]
+fn id() {
+    assert_eq!(TEST_VALUE.id(), TEST_VALUE.id());
+    assert_ne!(TEST_VALUE.id(), ANOTHER_TEST_VALUE.id());
+}
+
+#[test]
+fn id_before_first_use() {
+    inheritable_task_local! {
+        static FRESH_A: u32;
+        static FRESH_B: u32;
+        static FRESH_C: String;
+    }
+
+    let before = FRESH_A.id();
+    let from_thread = std::thread::spawn(|| FRESH_A.id()).join().unwrap();
+    FRESH_A.sync_scope(1, || assert_eq!(FRESH_A.id(), before));
+    assert_eq!(from_thread, before);
+    let ids = [FRESH_A.id(), FRESH_B.id(), FRESH_C.id(), TEST_VALUE.id()];
+    for (i, a) in ids.iter().enumerate() {
+        assert!(ids[i + 1..].iter().all(|b| a != b));
+    }
+}
+
+#[tokio::test]
+async fn builder() {
+    inheritable_task_local! {
+        static THIRD: u8;
+    }
+
+    let out = tokio_inherit_task_local::builder()
+        .set(&TEST_VALUE, 5)
+        .set(&ANOTHER_TEST_VALUE, String::from("foo"))
+        .set(&THIRD, 3)
+        .scope(async {
+            with3(&TEST_VALUE, &ANOTHER_TEST_VALUE, &THIRD, |&a, b, &c| {
+                (a, b.clone(), c)
+            })
+        })
+        .await;
+    assert_eq!(out, (5, String::from("foo"), 3));
+
+    let out = TEST_VALUE.sync_scope(5, || {
+        tokio_inherit_task_local::builder()
+            .set(&THIRD, 3)
+            .sync_scope(|| with2(&TEST_VALUE, &THIRD, |&a, &c| (a, c)))
+    });
+    assert_eq!(out, (5, 3));
+}
+
+#[tokio::test]
+async fn inherit_boxed() {
+    let futures = TEST_VALUE.sync_scope(5, || {
+        let futures: Vec<Pin<Box<dyn Future<Output = u32> + Send>>> = vec![
+            async { TEST_VALUE.with(|&v| v) }.inherit_task_local_boxed(),
+            async { TEST_VALUE.with(|&v| v + 1) }.inherit_task_local_boxed(),
+            async { TEST_VALUE.with(|&v| v + 2) }.inherit_task_local_boxed(),
+        ];
+        futures
+    });
+    let mut out = Vec::new();
+    for future in futures {
+        out.push(tokio::spawn(future).await.unwrap());
+    }
+    assert_eq!(out, [5, 6, 7]);
+}
+
+#[tokio::test]
+async fn scope_empty() {
+    let (out, inherited) = TEST_VALUE
+        .scope(5, async {
+            tokio_inherit_task_local::scope_empty(async {
+                let inherited =
+                    tokio::spawn(async { TEST_VALUE.try_with(|&v| v) }.inherit_task_local());
+                (TEST_VALUE.try_with(|&v| v), inherited.await.unwrap())
+            })
+            .await
+        })
+        .await;
+    assert_eq!(out, Err(InheritableAccessError::NotInTable));
+    assert_eq!(inherited, Err(InheritableAccessError::NotInTable));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dropped_once_after_descendants_complete() {
+    inheritable_task_local! {
+        static COUNTED: DropCounter;
+    }
+
+    let drops = Arc::new(AtomicUsize::new(0));
+    let handles = COUNTED
+        .scope(DropCounter(drops.clone()), async {
+            (0..8)
+                .map(|_| {
+                    tokio::spawn(
+                        async {
+                            tokio::task::yield_now().await;
+                            tokio::spawn(async { COUNTED.with(|_| ()) }.inherit_task_local())
+                                .await
+                                .unwrap();
+                        }
+                        .inherit_task_local(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        })
+        .await;
+    // The scope has completed, but its descendants may still be running.
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    assert_eq!(drops.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn scope_also() {
+    tokio::task_local! {
+        static STD_TEST_VALUE: u32;
+    }
+
+    let out = TEST_VALUE
+        .scope_also(&STD_TEST_VALUE, 5, async {
+            (TEST_VALUE.with(|&v| v), STD_TEST_VALUE.with(|&v| v))
+        })
+        .await;
+    assert_eq!(out, (5, 5));
+}
+
+#[tokio::test]
+async fn current_table() {
+    assert!(tokio_inherit_task_local::current_table().is_none());
+    let table = TEST_VALUE
+        .scope(7, async { tokio_inherit_task_local::current_table() })
+        .await
+        .unwrap();
+    assert_eq!(
+        table.clone().enter_scope(async { TEST_VALUE.get() }).await,
+        7
+    );
+    assert_eq!(table.enter_sync_scope(|| TEST_VALUE.get()), 7);
+    tokio_inherit_task_local::TaskLocalInheritableTable::default()
+        .enter_sync_scope(|| assert!(!TEST_VALUE.is_set()));
+}
+
+#[tokio::test]
+#[should_panic(expected = "cannot enter an inheritable scope while inside with/try_with")]
+async fn scope_inside_with() {
+    TEST_VALUE
+        .scope(1, async {
+            TEST_VALUE.with(|_| ANOTHER_TEST_VALUE.sync_scope(String::new(), || ()));
+        })
+        .await;
+}
+
+#[tokio::test]
+#[should_panic(expected = "cannot modify an inheritable task local while inside with/try_with")]
+async fn update_inside_with() {
+    TEST_VALUE
+        .scope(1, async {
+            TEST_VALUE.with(|_| TEST_VALUE.update(|v| v + 1))
+        })
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn with_async() {
+    let out = ANOTHER_TEST_VALUE
+        .scope(String::from("value"), async {
+            ANOTHER_TEST_VALUE
+                .with_async(|v| async move {
+                    tokio::task::yield_now().await;
+                    v.len()
+                })
+                .await
+        })
+        .await;
+    assert_eq!(out, 5);
+}
+
+#[test]
+fn borrow_across_statements() {
+    ANOTHER_TEST_VALUE.sync_scope(String::from("borrowed"), || {
+        let value = ANOTHER_TEST_VALUE.borrow();
+        let len = value.len();
+        let upper = value.to_uppercase();
+        // Other reads don't conflict with the borrow.
+        assert_eq!(ANOTHER_TEST_VALUE.get(), *value);
+        assert_eq!((len, upper.as_str()), (8, "BORROWED"));
+        let pushed = ANOTHER_TEST_VALUE.push(String::from("pushed"));
+        assert_eq!(*value, "borrowed");
+        drop(pushed);
+        assert!(std::sync::Arc::ptr_eq(
+            &tokio_inherit_task_local::Ref::into_arc(value),
+            &ANOTHER_TEST_VALUE.get_arc()
+        ));
+    });
+}
+
+#[tokio::test]
+async fn scope_with_overrides() {
+    let template = TEST_VALUE.sync_scope(1, || {
+        ANOTHER_TEST_VALUE.sync_scope(String::from("inherited"), || {
+            tokio_inherit_task_local::CapturedContext::capture()
+        })
+    });
+    let read = || async { (TEST_VALUE.get(), ANOTHER_TEST_VALUE.get()) };
+    let overridden = template
+        .scope_with_overrides(|builder| builder.set(&TEST_VALUE, 2), read())
+        .await;
+    assert_eq!(overridden, (2, String::from("inherited")));
+    // The template itself is unchanged.
+    assert_eq!(template.scope(read()).await, (1, String::from("inherited")));
+}
+
+#[test]
+fn captured_context_across_runtimes() {
+    let runtime_a = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .build()
+        .unwrap();
+    let context = runtime_a.block_on(async {
+        let task = async { tokio_inherit_task_local::CapturedContext::capture() };
+        tokio::spawn(TEST_VALUE.scope(21, task)).await.unwrap()
+    });
+    runtime_a.shutdown_timeout(std::time::Duration::from_secs(1));
+
+    let runtime_b = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let value = runtime_b.block_on(async {
+        let task = async {
+            tokio::task::yield_now().await;
+            TEST_VALUE.with(|value| *value)
+        };
+        tokio::spawn(context.scope(task)).await.unwrap()
+    });
+    assert_eq!(value, 21);
+    assert_eq!(context.enter(|| TEST_VALUE.get()), 21);
+}
+
+#[test]
+fn wrap_closure_on_thread() {
+    let f = TEST_VALUE.sync_scope(5, || {
+        ANOTHER_TEST_VALUE.sync_scope(String::from("wrapped"), || {
+            tokio_inherit_task_local::CapturedContext::capture()
+                .wrap_closure(|| (TEST_VALUE.get(), ANOTHER_TEST_VALUE.get()))
+        })
+    });
+    assert!(!TEST_VALUE.is_set());
+    let output = std::thread::spawn(f).join().unwrap();
+    assert_eq!(output, (5, String::from("wrapped")));
+}
+
+#[tokio::test]
+async fn scope_catch_unwind_keeps_parent() {
+    TEST_VALUE
+        .scope(1, async {
+            let result = TEST_VALUE
+                .scope_catch_unwind(2, async {
+                    tokio::task::yield_now().await;
+                    TEST_VALUE.with(|_| panic!("inside with"));
+                })
+                .await;
+            assert!(result.is_err());
+            assert_eq!(TEST_VALUE.get(), 1);
+            // The panic happened inside `with`, which must not leave the thread marked as accessing.
+            TEST_VALUE.sync_scope(3, || assert_eq!(TEST_VALUE.get(), 3));
+            let child = tokio::spawn(async { TEST_VALUE.get() }.inherit_task_local());
+            assert_eq!(child.await.unwrap(), 1);
+        })
+        .await;
+    assert!(!TEST_VALUE.is_set());
+}
+
+#[cfg(feature = "tokio-util")]
+#[tokio::test]
+async fn scope_until_cancelled_cleanup() {
+    use tokio_inherit_task_local::scope_until_cancelled;
+    use tokio_util::sync::CancellationToken;
+
+    let log = DropLog::default();
+    let token = CancellationToken::new();
+    let work = {
+        let log = log.clone();
+        async move {
+            let _running = Logged("work", log);
+            std::future::pending::<()>().await
+        }
+    };
+    let task = tokio::spawn(scope_until_cancelled(
+        &TEST_LOGGED,
+        Logged("value", log.clone()),
+        token.clone(),
+        work,
+        |value| value.1.lock().unwrap().push("cleanup"),
+    ));
+    tokio::task::yield_now().await;
+    token.cancel();
+    assert!(task.await.unwrap().is_none());
+    // The work is torn down first, then cleanup reads the value before it's dropped.
+    assert_eq!(*log.lock().unwrap(), ["work", "cleanup", "value"]);
+
+    let completed = scope_until_cancelled(
+        &TEST_VALUE,
+        1,
+        CancellationToken::new(),
+        async { TEST_VALUE.get() },
+        |_| unreachable!(),
+    )
+    .await;
+    assert_eq!(completed, Some(1));
+}
+
+#[tokio::test]
+async fn scope_shared_detached() {
+    fn start() -> tokio_inherit_task_local::SharedScope {
+        ANOTHER_TEST_VALUE.sync_scope(String::from("outer"), || TEST_VALUE.scope_shared(9))
+    }
+
+    let shared = start();
+    let (send, mut recv) = tokio::sync::mpsc::unbounded_channel();
+    for _ in 0..3 {
+        let send = send.clone();
+        drop(shared.spawn(async move {
+            tokio::task::yield_now().await;
+            send.send((TEST_VALUE.get(), ANOTHER_TEST_VALUE.get()))
+                .unwrap();
+        }));
+    }
+    drop((send, shared));
+    let mut outputs = Vec::new();
+    while let Some(output) = recv.recv().await {
+        outputs.push(output);
+    }
+    assert_eq!(outputs, vec![(9, String::from("outer")); 3]);
+    assert!(!TEST_VALUE.is_set());
+}
+
+#[tokio::test]
+async fn scope_counted_children() {
+    const CHILDREN: usize = 4;
+
+    // Keeps the scope and its children running until the count has been checked.
+    let gate = Arc::new(tokio::sync::Semaphore::new(0));
+    let (future, counter) = TEST_VALUE.scope_counted(1, {
+        let gate = gate.clone();
+        async move {
+            let children = (0..CHILDREN)
+                .map(|_| {
+                    let gate = gate.clone();
+                    let child = async move {
+                        let _permit = gate.acquire().await.unwrap();
+                        TEST_VALUE.get()
+                    };
+                    tokio::spawn(child.inherit_task_local())
+                })
+                .collect::<Vec<_>>();
+            let _permit = gate.acquire().await.unwrap();
+            children
+        }
+    });
+    let scope = tokio::spawn(future);
+    while counter.count() < CHILDREN {
+        tokio::task::yield_now().await;
+    }
+    assert_eq!(counter.count(), CHILDREN);
+    gate.add_permits(CHILDREN + 1);
+    for child in scope.await.unwrap() {
+        assert_eq!(child.await.unwrap(), 1);
+    }
+    assert_eq!(counter.count(), 0);
+}
+
+#[tokio::test]
+async fn replace_arc_between_children() {
+    let old = Arc::new(String::from("old"));
+    let new = Arc::new(String::from("new"));
+    let (before, after, displaced) = ANOTHER_TEST_VALUE.sync_scope(String::new(), || {
+        ANOTHER_TEST_VALUE.replace_arc(old.clone()).unwrap();
+        let child = || tokio::spawn(async { ANOTHER_TEST_VALUE.get_arc() }.inherit_task_local());
+        let before = child();
+        let displaced = ANOTHER_TEST_VALUE.replace_arc(new.clone()).unwrap();
+        (before, child(), displaced)
+    });
+    assert!(Arc::ptr_eq(&displaced.unwrap(), &old));
+    assert!(Arc::ptr_eq(&before.await.unwrap(), &old));
+    assert!(Arc::ptr_eq(&after.await.unwrap(), &new));
+    assert_eq!(
+        ANOTHER_TEST_VALUE.replace_arc(new).unwrap_err(),
+        InheritableAccessError::NotInTokio
+    );
+}
+
+#[tokio::test]
+async fn get_or_insert_with_once() {
+    let calls = AtomicUsize::new(0);
+    let make = || {
+        calls.fetch_add(1, Ordering::Relaxed);
+        String::from("inserted")
+    };
+    assert_eq!(
+        ANOTHER_TEST_VALUE.get_or_insert_with(make).unwrap_err(),
+        InheritableAccessError::NotInTokio
+    );
+    tokio_inherit_task_local::scope_empty(async {
+        let first = ANOTHER_TEST_VALUE.get_or_insert_with(make).unwrap();
+        let second = ANOTHER_TEST_VALUE.get_or_insert_with(make).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        let child = tokio::spawn(async { ANOTHER_TEST_VALUE.get_arc() }.inherit_task_local());
+        assert!(Arc::ptr_eq(&first, &child.await.unwrap()));
+    })
+    .await;
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+    assert!(!ANOTHER_TEST_VALUE.is_set());
+}
+
+inheritable_task_local! {
+    #[stacked]
+    static TEST_STACKED: u32;
+}
+
+fn copied_stack(key: &'static tokio_inherit_task_local::InheritableLocalKey<u32>) -> Vec<u32> {
+    key.with_stack(|stack| stack.iter().map(|v| **v).collect())
+}
+
+#[tokio::test]
+async fn with_stack_nested() {
+    assert!(TEST_STACKED.with_stack(|stack| stack.is_empty()));
+    TEST_STACKED
+        .scope(1, async {
+            TEST_STACKED
+                .scope(2, async {
+                    TEST_STACKED
+                        .scope(3, async {
+                            let child = async { copied_stack(&TEST_STACKED) };
+                            let inherited = tokio::spawn(child.inherit_task_local());
+                            assert_eq!(inherited.await.unwrap(), [1, 2, 3]);
+                            assert_eq!(TEST_STACKED.get(), 3);
+                        })
+                        .await;
+                    assert_eq!(copied_stack(&TEST_STACKED), [1, 2]);
+                })
+                .await;
+        })
+        .await;
+    // Keys that aren't stacked only see the current value.
+    TEST_VALUE.sync_scope(1, || {
+        TEST_VALUE.sync_scope(2, || {
+            assert_eq!(copied_stack(&TEST_VALUE), [2]);
+        })
+    });
+}
+
+#[test]
+fn with_stack_update() {
+    TEST_STACKED.sync_scope(1, || {
+        TEST_STACKED.update(|v| v + 1).unwrap();
+        assert_eq!(copied_stack(&TEST_STACKED), [2]);
+        TEST_STACKED.sync_scope(3, || {
+            TEST_STACKED.update(|v| v + 1).unwrap();
+            assert_eq!(copied_stack(&TEST_STACKED), [2, 4]);
+        });
+    });
+}
+
+#[tokio::test]
+async fn inherit_only_listed_keys() {
+    let child = TEST_VALUE.sync_scope(8, || {
+        ANOTHER_TEST_VALUE.sync_scope(String::from("left out"), || {
+            let child = async {
+                (
+                    TEST_VALUE.get(),
+                    ANOTHER_TEST_VALUE.try_with(|_| ()).unwrap_err(),
+                )
+            };
+            child.inherit_only(&[&TEST_VALUE])
+        })
+    });
+    assert_eq!(
+        tokio::spawn(child).await.unwrap(),
+        (8, InheritableAccessError::NotInTable)
+    );
+}
+
+#[test]
+fn push_pops_in_order() {
+    TEST_VALUE.sync_scope(0, || {
+        let first = TEST_VALUE.push(1);
+        let second = TEST_VALUE.push(2);
+        let third = TEST_VALUE.push(3);
+        assert_eq!(TEST_VALUE.get(), 3);
+        drop(third);
+        assert_eq!(TEST_VALUE.get(), 2);
+        drop(second);
+        assert_eq!(TEST_VALUE.get(), 1);
+        drop(first);
+        assert_eq!(TEST_VALUE.get(), 0);
+    });
+    tokio_inherit_task_local::TaskLocalInheritableTable::default().enter_sync_scope(|| {
+        let pushed = TEST_VALUE.push(1);
+        assert_eq!(TEST_VALUE.get(), 1);
+        drop(pushed);
+        assert!(!TEST_VALUE.is_set());
+    });
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(expected = "PopGuard dropped while a value pushed after it was still set")]
+fn push_popped_out_of_order() {
+    TEST_VALUE.sync_scope(0, || {
+        let first = TEST_VALUE.push(1);
+        let _second = TEST_VALUE.push(2);
+        drop(first);
+    });
+}
+
+#[tokio::test]
+async fn no_inherit() {
+    inheritable_task_local! {
+        #[no_inherit]
+        #[allow(dead_code)]
+        static SECRET: String;
+    }
+
+    let (secret, value) = SECRET
+        .scope(String::from("secret"), async {
+            assert_eq!(SECRET.get(), "secret");
+            TEST_VALUE
+                .scope(3, async {
+                    tokio::spawn(
+                        async { (SECRET.try_with(|_| ()), TEST_VALUE.get()) }.inherit_task_local(),
+                    )
+                    .await
+                    .unwrap()
+                })
+                .await
+        })
+        .await;
+    assert_eq!(secret, Err(InheritableAccessError::NotInTable));
+    assert_eq!(value, 3);
+}
+
+#[tokio::test]
+async fn get_opt() {
+    assert_eq!(TEST_VALUE.get_opt(), None);
+    tokio_inherit_task_local::scope_empty(async { assert_eq!(TEST_VALUE.get_opt(), None) }).await;
+    ANOTHER_TEST_VALUE
+        .scope(String::new(), async {
+            assert_eq!(TEST_VALUE.get_opt(), None)
+        })
+        .await;
+    TEST_VALUE
+        .scope(4, async { assert_eq!(TEST_VALUE.get_opt(), Some(4)) })
+        .await;
+    std::thread::spawn(|| assert_eq!(TEST_VALUE.get_opt(), None))
+        .join()
+        .unwrap();
+}
+
+#[cfg(feature = "tower")]
+#[tokio::test]
+async fn tower_layer() {
+    use tower::{ServiceBuilder, ServiceExt as _};
+
+    let service = ServiceBuilder::new()
+        .layer(tokio_inherit_task_local::InheritTaskLocalLayer::new(
+            &ANOTHER_TEST_VALUE,
+            |request: &String| request.to_uppercase(),
+        ))
+        .service_fn(|request: String| async move {
+            let child = tokio::spawn(async { ANOTHER_TEST_VALUE.get() }.inherit_task_local());
+            Ok::<_, std::convert::Infallible>((request, child.await.unwrap()))
+        });
+    let out = service.oneshot(String::from("hello")).await.unwrap();
+    assert_eq!(out, (String::from("hello"), String::from("HELLO")));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn scope_returning() {
+    inheritable_task_local! {
+        static COUNTER: AtomicUsize;
+    }
+
+    let (out, counter) = COUNTER
+        .scope_returning(AtomicUsize::new(0), async {
+            let child = async { COUNTER.with(|c| c.fetch_add(1, Ordering::SeqCst)) };
+            tokio::spawn(child.inherit_task_local()).await.unwrap();
+            5
+        })
+        .await;
+    assert_eq!(out, 5);
+    assert_eq!(counter.load(Ordering::SeqCst), 1);
+
+    let (_, value) = TEST_VALUE
+        .scope_returning(1, async { TEST_VALUE.update(|v| v + 1).unwrap() })
+        .await;
+    assert_eq!(*value, 2);
+}
+
+#[tokio::test]
+async fn scope_with_cleanup() {
+    static CLEANUPS: AtomicUsize = AtomicUsize::new(0);
+
+    let (release, released) = tokio::sync::oneshot::channel::<()>();
+    let mut child = None;
+    TEST_VALUE
+        .scope_with_cleanup(
+            1,
+            || {
+                CLEANUPS.fetch_add(1, Ordering::SeqCst);
+            },
+            async {
+                let task = async {
+                    released.await.unwrap();
+                    TEST_VALUE.get()
+                };
+                child = Some(tokio::spawn(task.inherit_task_local()));
+            },
+        )
+        .await;
+    assert_eq!(CLEANUPS.load(Ordering::SeqCst), 0);
+    release.send(()).unwrap();
+    assert_eq!(child.unwrap().await.unwrap(), 1);
+    assert_eq!(CLEANUPS.load(Ordering::SeqCst), 1);
+}
+
+#[cfg(feature = "rayon")]
+#[tokio::test]
+async fn rayon_join() {
+    use tokio_inherit_task_local::{join_inheriting, with_inherited_context};
+
+    let out = TEST_VALUE
+        .scope(6, async {
+            let a = with_inherited_context(|| TEST_VALUE.get());
+            let b = a.clone();
+            let joined = rayon::join(a, b);
+            (
+                joined,
+                join_inheriting(|| TEST_VALUE.get(), || TEST_VALUE.get()),
+            )
+        })
+        .await;
+    assert_eq!(out, ((6, 6), (6, 6)));
+}
+
+#[tokio::test]
+async fn captured_context() {
+    let context = TEST_VALUE.sync_scope(8, tokio_inherit_task_local::CapturedContext::capture);
+    for _ in 0..3 {
+        assert_eq!(context.enter(|| TEST_VALUE.get()), 8);
+    }
+    let child = tokio::spawn({
+        let context = context.clone();
+        async move { context.scope(async { TEST_VALUE.get() }).await }
+    });
+    assert_eq!(child.await.unwrap(), 8);
+}
+
+#[tokio::test]
+async fn writes_do_not_affect_shared_tables() {
+    TEST_VALUE
+        .scope(1, async {
+            let child = async {
+                let before = TEST_VALUE.get();
+                TEST_VALUE.update(|v| v * 10).unwrap();
+                (before, TEST_VALUE.get())
+            }
+            .inherit_task_local();
+            let context = tokio_inherit_task_local::CapturedContext::capture();
+            TEST_VALUE.update(|v| v + 1).unwrap();
+            assert_eq!(child.await, (1, 10));
+            assert_eq!(TEST_VALUE.get(), 2);
+            assert_eq!(
+                context.enter(|| TEST_VALUE.take()).unwrap().as_deref(),
+                Some(&1)
+            );
+            assert_eq!(context.enter(|| TEST_VALUE.get()), 1);
+            assert_eq!(TEST_VALUE.get(), 2);
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn spawn_with() {
+    let out = TEST_VALUE
+        .scope(1, async {
+            ANOTHER_TEST_VALUE
+                .scope(String::from("parent"), async {
+                    let child = tokio_inherit_task_local::spawn_with(
+                        &ANOTHER_TEST_VALUE,
+                        String::from("child"),
+                        async { (TEST_VALUE.get(), ANOTHER_TEST_VALUE.get()) },
+                    );
+                    let child = child.await.unwrap();
+                    (child, ANOTHER_TEST_VALUE.get())
+                })
+                .await
+        })
+        .await;
+    assert_eq!(out, ((1, String::from("child")), String::from("parent")));
+}
+
+#[tokio::test]
+async fn key_missing_from_inherited_table() {
+    inheritable_task_local! {
+        static NEVER_SET: u64;
+    }
+
+    let child = TEST_VALUE.sync_scope(1, || {
+        async { NEVER_SET.try_with(|_| ()) }.inherit_task_local()
+    });
+    assert_eq!(child.await, Err(InheritableAccessError::NotInTable));
+    let table = TEST_VALUE
+        .sync_scope(1, tokio_inherit_task_local::current_table)
+        .unwrap();
+    assert!(!table.contains(NEVER_SET.id()));
+    assert!(table.get_raw(NEVER_SET.id()).is_none());
+}
+
+#[tokio::test]
+async fn inherit_nothing() {
+    let child = tokio::spawn(
+        async {
+            let missing = TEST_VALUE.try_with(|_| ());
+            TEST_VALUE.get_or_insert_with(|| 4).unwrap();
+            (missing, TEST_VALUE.get())
+        }
+        .inherit_task_local(),
+    );
+    assert_eq!(
+        child.await.unwrap(),
+        (Err(InheritableAccessError::NotInTable), 4)
+    );
+    assert_eq!(
+        TEST_VALUE.try_with(|_| ()),
+        Err(InheritableAccessError::NotInTokio)
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_arc() {
+    ANOTHER_TEST_VALUE
+        .scope(String::from("shared"), async {
+            let parent = ANOTHER_TEST_VALUE.get_arc();
+            let child = tokio::spawn(
+                async {
+                    let child = ANOTHER_TEST_VALUE.get_arc();
+                    assert!(ANOTHER_TEST_VALUE.ptr_eq_current(&child));
+                    child
+                }
+                .inherit_task_local(),
+            );
+            assert!(Arc::ptr_eq(&parent, &child.await.unwrap()));
+            assert!(!ANOTHER_TEST_VALUE.ptr_eq_current(&Arc::new(String::from("shared"))));
+        })
+        .await;
+    assert!(!ANOTHER_TEST_VALUE.ptr_eq_current(&Arc::new(String::new())));
+}
+
+#[tokio::test]
+async fn try_get_arc() {
+    struct NotClone(u32);
+
+    inheritable_task_local! {
+        static NOT_CLONE: NotClone;
+    }
+
+    assert_eq!(
+        NOT_CLONE.try_get_arc().map(|v| v.0),
+        Err(InheritableAccessError::NotInTokio)
+    );
+    let (set, unset) = NOT_CLONE
+        .scope(NotClone(17), async {
+            (
+                NOT_CLONE.try_get_arc().map(|v| v.0),
+                ANOTHER_TEST_VALUE.try_get_arc(),
+            )
+        })
+        .await;
+    assert_eq!(set, Ok(17));
+    assert_eq!(unset, Err(InheritableAccessError::NotInTable));
+}
+
+static INITIALIZER_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+inheritable_task_local! {
+    static LAZY_VALUE: u32 = || {
+        INITIALIZER_CALLS.fetch_add(1, Ordering::SeqCst);
+        42
+    };
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn lazy_initializer() {
+    let tasks = (0..8)
+        .map(|_| tokio::spawn(async { LAZY_VALUE.get() }))
+        .collect::<Vec<_>>();
+    for task in tasks {
+        assert_eq!(task.await.unwrap(), 42);
+    }
+    assert_eq!(LAZY_VALUE.get(), 42);
+    assert_eq!(LAZY_VALUE.scope(1, async { LAZY_VALUE.get() }).await, 1);
+    assert_eq!(INITIALIZER_CALLS.load(Ordering::SeqCst), 1);
+}
+
+mod first_library {
+    tokio_inherit_task_local::inheritable_task_local! {
+        pub static FOO: u32;
+    }
+}
+
+mod second_library {
+    tokio_inherit_task_local::inheritable_task_local! {
+        pub static FOO: u32;
+    }
+}
+
+#[tokio::test]
+async fn same_name_in_different_modules() {
+    assert_eq!(first_library::FOO.key_name(), "full::first_library::FOO");
+    assert_eq!(second_library::FOO.key_name(), "full::second_library::FOO");
+    assert_ne!(first_library::FOO.id(), second_library::FOO.id());
+    first_library::FOO
+        .scope(1, async { assert!(!second_library::FOO.is_set()) })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reuse_snapshot() {
+    fn assert_shareable<T: Clone + Send + Sync + 'static>(_: &T) {}
+
+    let snapshot = TEST_VALUE.sync_scope(9, tokio_inherit_task_local::capture);
+    assert_shareable(&snapshot);
+    let snapshot = Arc::new(snapshot);
+    let tasks = (0..2)
+        .map(|i| {
+            let snapshot = snapshot.clone();
+            tokio::spawn(async move {
+                snapshot
+                    .scope(async move {
+                        TEST_VALUE.update(|v| v + i).unwrap();
+                        TEST_VALUE.get()
+                    })
+                    .await
+            })
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(snapshot.enter(|| TEST_VALUE.get()), 9);
+    let mut outputs = Vec::new();
+    for task in tasks {
+        outputs.push(task.await.unwrap());
+    }
+    assert_eq!(outputs, [9, 10]);
+    assert_eq!(snapshot.enter(|| TEST_VALUE.get()), 9);
+}
+
+#[tokio::test]
+async fn occupancy() {
+    use tokio_inherit_task_local::{current_occupancy, registered_key_count, scope_empty};
+
+    assert_eq!(current_occupancy(), None);
+    assert_eq!(scope_empty(async { current_occupancy() }).await, Some(0));
+    let (partial, full) = TEST_VALUE
+        .scope(1, async {
+            let partial = current_occupancy();
+            let full = ANOTHER_TEST_VALUE
+                .scope(String::new(), async { current_occupancy() })
+                .await;
+            (partial, full)
+        })
+        .await;
+    assert_eq!((partial, full), (Some(1), Some(2)));
+    assert!(registered_key_count() >= 2);
+}
+
+#[tokio::test]
+async fn scope_opt() {
+    let some = TEST_VALUE
+        .scope_opt(Some(3), async { TEST_VALUE.get_opt() })
+        .await;
+    assert_eq!(some, Some(3));
+    let none = TEST_VALUE
+        .scope_opt(None, async { TEST_VALUE.try_with(|_| ()) })
+        .await;
+    assert_eq!(none, Err(InheritableAccessError::NotInTokio));
+    let outer = TEST_VALUE
+        .scope(1, TEST_VALUE.scope_opt(None, async { TEST_VALUE.get() }))
+        .await;
+    assert_eq!(outer, 1);
+}
+
+#[cfg(feature = "tracing-subscriber")]
+#[test]
+fn span_context_layer() {
+    use tracing_subscriber::layer::SubscriberExt as _;
+
+    let subscriber =
+        tracing_subscriber::registry().with(tokio_inherit_task_local::SpanContextLayer::default());
+    let span = tracing::subscriber::with_default(subscriber, || {
+        TEST_VALUE.sync_scope(11, || tracing::info_span!("test"))
+    });
+    let out = std::thread::spawn(move || {
+        let inside = span.in_scope(|| {
+            let inherited = thread_spawn_inheriting(|| TEST_VALUE.get()).join().unwrap();
+            (TEST_VALUE.get(), inherited)
+        });
+        (inside, TEST_VALUE.get_opt())
+    })
+    .join()
+    .unwrap();
+    assert_eq!(out, ((11, 11), None));
+}
+
+#[cfg(all(feature = "tracing", feature = "tracing-subscriber"))]
+#[test]
+fn scope_in_span() {
+    use std::{fmt::Debug, sync::Mutex};
+    use tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id},
+        Subscriber,
+    };
+    use tracing_subscriber::layer::{Context, Layer, SubscriberExt as _};
+
+    struct FieldRecorder(Arc<Mutex<Vec<String>>>);
+
+    impl<S: Subscriber> Layer<S> for FieldRecorder {
+        fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+            struct Visitor<'a>(&'a mut Vec<String>);
+
+            impl Visit for Visitor<'_> {
+                fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+                    self.0.push(format!("{}={value:?}", field.name()));
+                }
+            }
+
+            attrs.record(&mut Visitor(&mut self.0.lock().unwrap()));
+        }
+    }
+
+    let fields = Arc::new(Mutex::new(Vec::new()));
+    let subscriber = tracing_subscriber::registry().with(FieldRecorder(fields.clone()));
+    let span_name = tracing::subscriber::with_default(subscriber, || {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(TEST_VALUE.scope_in_span(16, async {
+                tokio::task::yield_now().await;
+                assert_eq!(TEST_VALUE.get(), 16);
+                tracing::Span::current().metadata().map(|m| m.name())
+            }))
+    });
+    assert_eq!(span_name, Some("inheritable_scope"));
+    let fields = fields.lock().unwrap();
+    assert_eq!(fields.len(), 2);
+    assert!(fields[0].starts_with("key=") && fields[0].contains("TEST_VALUE"));
+    assert_eq!(fields[1], "value=16");
+}
+
+#[cfg(all(feature = "tracing", feature = "tracing-subscriber"))]
+#[test]
+fn inherit_task_local_and_span() {
+    let output = tracing::subscriber::with_default(tracing_subscriber::registry(), || {
+        let future = tracing::info_span!("parent").in_scope(|| {
+            TEST_VALUE.sync_scope(17, || {
+                async {
+                    tokio::task::yield_now().await;
+                    let span = tracing::Span::current().metadata().map(|m| m.name());
+                    (span, TEST_VALUE.get())
+                }
+                .inherit_task_local_and_span()
+            })
+        });
+        assert!(tracing::Span::current().is_none());
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(async { tokio::spawn(future).await.unwrap() })
+    });
+    assert_eq!(output, (Some("parent"), 17));
+}
+
+inheritable_task_local! {
+    static TEST_LIST: Vec<u32>;
+}
+
+mod gated {
+    use tokio_inherit_task_local::{inheritable_task_local, InheritableLocalKey, KeyOptions};
+
+    pub const LINE: u32 = line!() + 1;
+    inheritable_task_local! {
+        #[cfg(any())]
+        pub static BEFORE: u32;
+        pub static AFTER: u32;
+    }
+
+    /// What `AFTER` would be if `BEFORE` weren't declared at all.
+    pub fn alone() -> &'static InheritableLocalKey<u32> {
+        let site = format!("{}:{LINE}:5", file!());
+        Box::leak(Box::new(InheritableLocalKey::__new(
+            concat!(module_path!(), "::AFTER"),
+            Box::leak(site.into_boxed_str()),
+            option_env!("CARGO_PKG_VERSION"),
+            KeyOptions::new(),
+        )))
+    }
+}
+
+#[test]
+fn cfg_gated_key_keeps_ids() {
+    assert_eq!(gated::AFTER.id(), gated::alone().id());
+    assert_ne!(gated::AFTER.id(), TEST_VALUE.id());
+    assert_eq!(gated::AFTER.sync_scope(3, || gated::AFTER.get()), 3);
+}
+
+type DropLog = Arc<std::sync::Mutex<Vec<&'static str>>>;
+
+/// Records its name in the log when dropped.
+struct Logged(&'static str, DropLog);
+
+impl Drop for Logged {
+    fn drop(&mut self) {
+        self.1.lock().unwrap().push(self.0);
+    }
+}
+
+inheritable_task_local! {
+    static TEST_LOGGED: Logged;
+}
+
+#[tokio::test]
+async fn drop_order_on_completion() {
+    let log = DropLog::default();
+    TEST_LOGGED
+        .scope(Logged("outer", log.clone()), async {
+            TEST_LOGGED
+                .scope(Logged("inner", log.clone()), async {
+                    tokio::task::yield_now().await;
+                })
+                .await;
+            log.lock().unwrap().push("outer body");
+        })
+        .await;
+    assert_eq!(*log.lock().unwrap(), ["inner", "outer body", "outer"]);
+}
+
+#[tokio::test]
+async fn drop_order_on_early_return() {
+    async fn inner(log: &DropLog) -> Result<(), ()> {
+        TEST_LOGGED
+            .scope(Logged("inner", log.clone()), async {
+                Err::<(), ()>(())?;
+                unreachable!()
+            })
+            .await
+    }
+
+    let log = DropLog::default();
+    let result = TEST_LOGGED
+        .scope(Logged("outer", log.clone()), async {
+            inner(&log).await?;
+            log.lock().unwrap().push("unreachable");
+            Ok::<(), ()>(())
+        })
+        .await;
+    assert!(result.is_err());
+    assert_eq!(*log.lock().unwrap(), ["inner", "outer"]);
+}
+
+#[tokio::test]
+async fn drop_order_on_cancellation() {
+    let log = DropLog::default();
+    let future = TEST_LOGGED.scope(Logged("outer", log.clone()), async {
+        TEST_LOGGED
+            .scope(Logged("inner", log.clone()), std::future::pending::<()>())
+            .await;
+    });
+    let cancelled = tokio::time::timeout(std::time::Duration::from_millis(1), future).await;
+    assert!(cancelled.is_err());
+    assert_eq!(*log.lock().unwrap(), ["inner", "outer"]);
+}
+
+#[tokio::test]
+async fn drop_order_with_detached_child() {
+    let log = DropLog::default();
+    let (finish, finished) = tokio::sync::oneshot::channel::<()>();
+    let mut child = None;
+    TEST_LOGGED
+        .scope(Logged("outer", log.clone()), async {
+            child = Some(tokio::spawn(
+                async {
+                    finished.await.unwrap();
+                    TEST_LOGGED.with(|logged| logged.1.lock().unwrap().push("child"));
+                }
+                .inherit_task_local(),
+            ));
+            log.lock().unwrap().push("outer body");
+        })
+        .await;
+    // The scope has completed, but the detached child still holds the value.
+    assert_eq!(*log.lock().unwrap(), ["outer body"]);
+    finish.send(()).unwrap();
+    child.unwrap().await.unwrap();
+    assert_eq!(*log.lock().unwrap(), ["outer body", "child", "outer"]);
+}
+
+#[tokio::test]
+async fn scope_into_future() {
+    use std::future::IntoFuture;
+
+    /// Builds a future which reads `TEST_VALUE`, the way request builders are awaited directly.
+    struct Request {
+        offset: u32,
+    }
+
+    impl IntoFuture for Request {
+        type Output = u32;
+        type IntoFuture = Pin<Box<dyn Future<Output = u32> + Send>>;
+
+        fn into_future(self) -> Self::IntoFuture {
+            Box::pin(async move { TEST_VALUE.get() + self.offset })
+        }
+    }
+
+    assert_eq!(TEST_VALUE.scope(20, Request { offset: 1 }).await, 21);
+    let context = TEST_VALUE.sync_scope(30, tokio_inherit_task_local::CapturedContext::capture);
+    assert_eq!(context.scope(Request { offset: 2 }).await, 32);
+    let built = tokio_inherit_task_local::builder()
+        .set(&TEST_VALUE, 40)
+        .scope(Request { offset: 3 });
+    assert_eq!(built.await, 43);
+    assert_eq!(TEST_VALUE.scope_owned(50, Request { offset: 4 }).await, 54);
+    assert_eq!(
+        TEST_VALUE.scope_opt(Some(60), Request { offset: 5 }).await,
+        65
+    );
+    assert_eq!(
+        TEST_VALUE.scope_override(70, Request { offset: 6 }).await,
+        76
+    );
+    let overridden =
+        context.scope_with_overrides(|b| b.set(&TEST_VALUE, 80), Request { offset: 7 });
+    assert_eq!(overridden.await, 87);
+    #[cfg(feature = "tokio-util")]
+    {
+        let completed = tokio_inherit_task_local::scope_until_cancelled(
+            &TEST_VALUE,
+            90,
+            tokio_util::sync::CancellationToken::new(),
+            Request { offset: 8 },
+            |_| unreachable!(),
+        );
+        assert_eq!(completed.await, Some(98));
+    }
+}
+
+#[tokio::test]
+async fn scope_with_appends() {
+    fn append(item: u32) -> impl FnOnce(Option<&Vec<u32>>) -> Vec<u32> {
+        move |list| {
+            let mut list = list.cloned().unwrap_or_default();
+            list.push(item);
+            list
+        }
+    }
+
+    TEST_LIST
+        .scope_with(append(1), async {
+            assert_eq!(TEST_LIST.get(), [1]);
+            let child = TEST_LIST.scope_with(append(2), async {
+                tokio::spawn(async { TEST_LIST.get() }.inherit_task_local())
+                    .await
+                    .unwrap()
+            });
+            assert_eq!(child.await, [1, 2]);
+            assert_eq!(TEST_LIST.get(), [1]);
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn context_clone_bytes_grows() {
+    use tokio_inherit_task_local::context_clone_bytes;
+
+    let empty = tokio_inherit_task_local::scope_empty(async { context_clone_bytes() }).await;
+    let one = TEST_VALUE.scope(1, async { context_clone_bytes() }).await;
+    let two = TEST_VALUE
+        .scope(1, async {
+            ANOTHER_TEST_VALUE
+                .scope(String::new(), async { context_clone_bytes() })
+                .await
+        })
+        .await;
+    assert!(empty > 0);
+    assert!(empty < one && one < two);
+    assert_eq!(context_clone_bytes(), 0);
+}
+
+#[test]
+fn registered_keys() {
+    TEST_VALUE.sync_scope(1, || ANOTHER_TEST_VALUE.sync_scope(String::new(), || ()));
+    let keys = tokio_inherit_task_local::registered_keys();
+    let name_of = |id| {
+        keys.iter()
+            .find(|&&(key, _)| key == id)
+            .map(|&(_, name)| name)
+    };
+    assert_eq!(name_of(TEST_VALUE.id()), Some("full::TEST_VALUE"));
+    assert_eq!(
+        name_of(ANOTHER_TEST_VALUE.id()),
+        Some("full::ANOTHER_TEST_VALUE")
+    );
+}
+
+#[tokio::test]
+async fn set_slots() {
+    let table = TEST_VALUE
+        .scope(1, async {
+            ANOTHER_TEST_VALUE
+                .scope(String::new(), async {
+                    tokio_inherit_task_local::current_table()
+                })
+                .await
+        })
+        .await
+        .unwrap();
+    assert_eq!(table.set_slots(), 2);
+    let registered = tokio_inherit_task_local::registered_key_count();
+    let ratio = table.set_slots() as f64 / registered as f64;
+    assert!(ratio > 0.0 && ratio <= 1.0);
+    assert_eq!(
+        tokio_inherit_task_local::TaskLocalInheritableTable::default().set_slots(),
+        0
+    );
+}
+
+#[tokio::test]
+async fn inherit_scoped() {
+    let mut seen = Vec::new();
+    let borrowing = TEST_VALUE.sync_scope(12, || {
+        async {
+            tokio::task::yield_now().await;
+            seen.push(TEST_VALUE.get());
+        }
+        .inherit_task_local_scoped()
+    });
+    borrowing.await;
+    assert_eq!(seen, [12]);
+}
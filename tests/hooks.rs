@@ -0,0 +1,238 @@
+//! Tests which install process-global hooks or settings. They live in their own test binary, so the hooks can't
+//! affect the tests in `full.rs` which run at the same time.
+
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tokio_inherit_task_local::{
+    inheritable_task_local, set_empty_inherit_hook, set_shadow_hook, set_unpolled_hook,
+    FutureInheritTaskLocal,
+};
+
+inheritable_task_local! {
+    static TEST_VALUE: u32;
+    static ANOTHER_TEST_VALUE: String;
+}
+
+#[test]
+fn table_capacity_hint() {
+    use tokio_inherit_task_local::{set_table_capacity_hint, TaskLocalInheritableTable};
+
+    // Run on a fresh thread, so the table doesn't reuse a larger allocation from the pool.
+    let (hinted, table) = std::thread::spawn(|| {
+        set_table_capacity_hint(40);
+        let hinted = TaskLocalInheritableTable::default().capacity();
+        set_table_capacity_hint(0);
+        let table = TEST_VALUE.sync_scope(1, || tokio_inherit_task_local::current_table().unwrap());
+        (hinted, table.capacity())
+    })
+    .join()
+    .unwrap();
+    assert!(hinted >= 40);
+    assert!(table >= 40, "the scope should reuse the hinted allocation");
+}
+
+#[test]
+fn empty_inherit_hook() {
+    static FIRED: AtomicBool = AtomicBool::new(false);
+    set_empty_inherit_hook(|| FIRED.store(true, Ordering::SeqCst));
+    let _ = tokio_inherit_task_local::inherit_task_local(|| ());
+    assert!(FIRED.load(Ordering::SeqCst));
+}
+
+#[test]
+fn shadow_hook() {
+    inheritable_task_local! {
+        static SHADOWED: u32;
+    }
+    static SHADOWS: AtomicUsize = AtomicUsize::new(0);
+
+    set_shadow_hook(|key| {
+        if key == SHADOWED.id() {
+            SHADOWS.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+    let out = SHADOWED.sync_scope(1, || {
+        assert_eq!(SHADOWS.load(Ordering::SeqCst), 0);
+        SHADOWED.sync_scope(2, || SHADOWED.get())
+    });
+    assert_eq!(out, 2);
+    assert_eq!(SHADOWS.load(Ordering::SeqCst), 1);
+}
+
+#[cfg(feature = "audit")]
+#[test]
+fn audit_hook_location() {
+    use std::{cell::RefCell, panic::Location};
+    use tokio_inherit_task_local::set_audit_hook;
+
+    thread_local! {
+        static ACCESSES: RefCell<Vec<(&'static str, &'static Location<'static>)>> =
+            const { RefCell::new(Vec::new()) };
+    }
+
+    set_audit_hook(|name, location| {
+        ACCESSES.with(|accesses| accesses.borrow_mut().push((name, location)))
+    });
+    inheritable_task_local! {
+        #[accessors(audited, try_audited)]
+        static AUDITED: u32;
+    }
+
+    TEST_VALUE.sync_scope(1, || {
+        let line = line!() + 1;
+        TEST_VALUE.with(|_| ());
+        TEST_VALUE.get();
+        TEST_VALUE.get_opt();
+        TEST_VALUE.map(|_| ()).unwrap();
+        let accesses = ACCESSES.with(|accesses| accesses.take());
+        let lines = accesses
+            .iter()
+            .map(|(name, location)| {
+                assert!(name.ends_with("TEST_VALUE"));
+                assert_eq!(location.file(), file!());
+                location.line()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [line, line + 1, line + 2, line + 3]);
+    });
+    AUDITED.sync_scope(2, || {
+        let line = line!() + 1;
+        audited();
+        try_audited().unwrap();
+        let accesses = ACCESSES.with(|accesses| accesses.take());
+        let lines = accesses
+            .iter()
+            .map(|(_, location)| location.line())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [line, line + 1]);
+    });
+}
+
+thread_local! {
+    /// How many unpolled futures were reported on this thread. Every test installs the same hook, so they can't
+    /// replace each other's.
+    static UNPOLLED: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count_unpolled() {
+    UNPOLLED.with(|u| u.set(u.get() + 1));
+}
+
+#[test]
+fn unpolled_hook() {
+    set_unpolled_hook(count_unpolled);
+    drop(TEST_VALUE.sync_scope(1, || async {}.inherit_task_local()));
+    assert_eq!(
+        UNPOLLED.with(Cell::get),
+        usize::from(cfg!(debug_assertions))
+    );
+
+    let polled = TEST_VALUE.sync_scope(1, || async {}.inherit_task_local());
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(polled);
+    assert_eq!(
+        UNPOLLED.with(Cell::get),
+        usize::from(cfg!(debug_assertions))
+    );
+}
+
+#[cfg(feature = "macros")]
+#[test]
+fn select_inheriting_doesnt_report_unpolled() {
+    use tokio_inherit_task_local::select_inheriting;
+
+    set_unpolled_hook(count_unpolled);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    // `select!` polls the branches in a random order, and drops the losing one without polling it if the winner
+    // completes first.
+    for _ in 0..20 {
+        let selected = runtime.block_on(TEST_VALUE.scope(1, async {
+            select_inheriting! {
+                v = async { TEST_VALUE.get() } => v,
+                () = std::future::pending() => unreachable!(),
+            }
+        }));
+        assert_eq!(selected, 1);
+    }
+    assert_eq!(UNPOLLED.with(Cell::get), 0);
+}
+
+#[tokio::test]
+async fn scope_observer() {
+    use std::sync::Mutex;
+    use tokio_inherit_task_local::{set_scope_observer, ScopeEvent};
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let thread = std::thread::current().id();
+    set_scope_observer(Box::new({
+        let events = events.clone();
+        // Other tests run on other threads at the same time.
+        move |event| {
+            if std::thread::current().id() == thread {
+                events.lock().unwrap().push(event);
+            }
+        }
+    }));
+    TEST_VALUE
+        .scope(1, async {
+            ANOTHER_TEST_VALUE
+                .scope(String::new(), async {
+                    tokio::spawn(async {}.inherit_task_local()).await.unwrap();
+                })
+                .await;
+        })
+        .await;
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            ScopeEvent::Enter {
+                key: TEST_VALUE.id()
+            },
+            ScopeEvent::Enter {
+                key: ANOTHER_TEST_VALUE.id()
+            },
+            ScopeEvent::Inherit { key_count: 2 },
+            ScopeEvent::Exit,
+            ScopeEvent::Exit,
+        ]
+    );
+
+    // The table outlives the scope, but the scope is reported as exited once the future completes.
+    events.lock().unwrap().clear();
+    let mut owned = TEST_VALUE.scope_owned(3, async {});
+    (&mut owned).await;
+    let enter_exit = [
+        ScopeEvent::Enter {
+            key: TEST_VALUE.id(),
+        },
+        ScopeEvent::Exit,
+    ];
+    assert_eq!(*events.lock().unwrap(), enter_exit);
+    let table = owned.into_table();
+    assert_eq!(*events.lock().unwrap(), enter_exit);
+    table.enter_scope(async {}).await;
+    assert_eq!(*events.lock().unwrap(), enter_exit);
+
+    // Dropping it before it completes exits the scope as well.
+    events.lock().unwrap().clear();
+    drop(TEST_VALUE.scope_owned(4, async {}));
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            ScopeEvent::Enter {
+                key: TEST_VALUE.id(),
+            },
+            ScopeEvent::Exit,
+        ]
+    );
+}
@@ -0,0 +1,47 @@
+#![cfg(feature = "log")]
+
+use std::sync::Mutex;
+
+use log::{Level, Log, Metadata, Record};
+use tokio_inherit_task_local::inheritable_task_local;
+
+inheritable_task_local! {
+    static RETRIES: u32;
+}
+
+struct CapturingLogger(Mutex<Vec<(Level, String)>>);
+
+impl Log for CapturingLogger {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        self.0
+            .lock()
+            .unwrap()
+            .push((record.level(), record.args().to_string()));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger(Mutex::new(Vec::new()));
+
+#[test]
+fn with_or_warn() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(log::LevelFilter::Warn);
+
+    assert_eq!(
+        RETRIES.sync_scope(3, || RETRIES.with_or_warn(|&v| v + 1)),
+        4
+    );
+    assert!(LOGGER.0.lock().unwrap().is_empty());
+
+    assert_eq!(RETRIES.with_or_warn(|&v| v + 1), 1);
+    let logs = LOGGER.0.lock().unwrap();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0].0, Level::Warn);
+    assert!(logs[0].1.contains("log::RETRIES"));
+}
@@ -0,0 +1,130 @@
+//! Procedural macros for [`tokio-inherit-task-local`](https://docs.rs/tokio-inherit-task-local). You are not meant to
+//! depend on this crate directly, enable the `macros` feature of `tokio-inherit-task-local` instead.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{
+    parse::ParseStream, parse_macro_input, spanned::Spanned, Data, DataStruct, DeriveInput, Error,
+    Fields, Ident, ItemFn, Path, ReturnType, Token,
+};
+
+/// Makes the future returned by an `async fn` inherit the inheritable task local values of its caller.
+///
+/// See the [`tokio-inherit-task-local` documentation](https://docs.rs/tokio-inherit-task-local) for details.
+#[proc_macro_attribute]
+pub fn inherit_task_local(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        let attr = proc_macro2::TokenStream::from(attr);
+        return Error::new(
+            attr.span(),
+            "`inherit_task_local` does not accept arguments",
+        )
+        .into_compile_error()
+        .into();
+    }
+    let ItemFn {
+        attrs,
+        vis,
+        mut sig,
+        block,
+    } = parse_macro_input!(item as ItemFn);
+    if sig.asyncness.take().is_none() {
+        return Error::new(
+            sig.fn_token.span(),
+            "`inherit_task_local` can only be applied to an `async fn`",
+        )
+        .into_compile_error()
+        .into();
+    }
+    let output = match &sig.output {
+        ReturnType::Default => quote!(()),
+        ReturnType::Type(_, ty) => quote!(#ty),
+    };
+    sig.output = syn::parse_quote!(-> impl ::std::future::Future<Output = #output>);
+    quote! {
+        #(#attrs)*
+        #vis #sig {
+            ::tokio_inherit_task_local::FutureInheritTaskLocal::inherit_task_local(async move #block)
+        }
+    }
+    .into()
+}
+
+/// Generates a `from_current` constructor for a struct, which reads each of its fields from an inheritable task
+/// local.
+///
+/// See the [`tokio-inherit-task-local` documentation](https://docs.rs/tokio-inherit-task-local) for details.
+#[proc_macro_derive(FromInheritableContext, attributes(inheritable))]
+pub fn derive_from_inheritable_context(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    match from_inheritable_context(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.into_compile_error().into(),
+    }
+}
+
+fn from_inheritable_context(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let fields = match &input.data {
+        Data::Struct(DataStruct {
+            fields: Fields::Named(fields),
+            ..
+        }) => &fields.named,
+        _ => {
+            return Err(Error::new(
+                input.ident.span(),
+                "`FromInheritableContext` can only be derived for structs with named fields",
+            ))
+        }
+    };
+    let initializers = fields
+        .iter()
+        .map(|field| {
+            let name = field.ident.as_ref().expect("fields are named");
+            let attr = field
+                .attrs
+                .iter()
+                .find(|attr| attr.path().is_ident("inheritable"))
+                .ok_or_else(|| {
+                    Error::new(
+                        name.span(),
+                        "every field needs an `#[inheritable(KEY)]` attribute naming the key to read it from",
+                    )
+                })?;
+            let (key, optional) = attr.parse_args_with(|input: ParseStream<'_>| {
+                let key: Path = input.parse()?;
+                let mut optional = false;
+                if input.parse::<Option<Token![,]>>()?.is_some() {
+                    let option: Ident = input.parse()?;
+                    if option != "optional" {
+                        return Err(Error::new(option.span(), "expected `optional`"));
+                    }
+                    optional = true;
+                }
+                Ok((key, optional))
+            })?;
+            Ok(if optional {
+                quote!(#name: ::tokio_inherit_task_local::InheritableLocalKey::get_opt(&#key))
+            } else {
+                quote! {
+                    #name: ::tokio_inherit_task_local::InheritableLocalKey::try_with(
+                        &#key,
+                        ::std::clone::Clone::clone,
+                    )?
+                }
+            })
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            /// Reads each field from the inheritable task local named by its `#[inheritable]` attribute.
+            pub fn from_current(
+            ) -> ::std::result::Result<Self, ::tokio_inherit_task_local::InheritableAccessError> {
+                ::std::result::Result::Ok(Self {
+                    #(#initializers,)*
+                })
+            }
+        }
+    })
+}